@@ -1,10 +1,14 @@
 use macroquad::window::next_frame;
+use std::io;
 use menu::{MenuVariant, Ui};
 
 use crate::network::{
     GameAgent,
+    bans::Bans,
     client::GameClient,
-    server::GameServer
+    discovery::Beacon,
+    server::GameServer,
+    solo::Solo
 };
 
 mod menu;
@@ -33,22 +37,70 @@ impl Application {
             // UI transitions special behaviours
             match (last, current.clone()) {
                 (MenuVariant::Join { name, ip, port }, MenuVariant::InGame ) => {
-                    let (_name, ip, port) = (name.unwrap(), ip.unwrap(), port.unwrap());
-                    
-                    let client = GameClient::new(&format!("{ip}:{port}"));
+                    let client = match (Self::filled(name.clone()), Self::filled(ip.clone()), Self::filled(port.clone())) {
+                        (Some(name), Some(ip), Some(port)) => GameClient::new(&format!("{ip}:{port}"), &name).map_err(|e| {
+                            eprintln!("DEBUG: Failed to connect : {e:?}");
+                            e.to_string()
+                        }),
+                        _ => Err(String::from("Enter a name, an address and a port to join"))
+                    };
                     
                     match client {
                         Ok(client) => {
                             self.game = Some(Box::new(client));
                         },
                         Err(e) => {
-                            eprintln!("DEBUG: Failed to connect : {e:?}");
-                            *current = MenuVariant::Join { name: None, ip: None, port: None }
+                            *current = MenuVariant::Join { name, ip, port };
+                            self.ui.notify(&e);
                         }
-                }
+                    }
                 },
-                (MenuVariant::Host { port }, MenuVariant::InGame) => {
-                    self.game = Some(Box::new(GameServer::new(&format!("0.0.0.0:{}", port.unwrap())).unwrap()));
+                (MenuVariant::Main, MenuVariant::InGame) => {
+                    match Solo::new("Player") {
+                        Ok(solo) => self.game = Some(Box::new(solo)),
+                        Err(e) => {
+                            *current = MenuVariant::Main;
+                            self.ui.notify(&e.to_string());
+                        }
+                    }
+                },
+                (MenuVariant::Host { port, name, .. }, MenuVariant::InGame) => {
+                    let server = match Self::filled(port.clone()) {
+                        Some(port) => {
+                            let name = Self::filled(name.clone()).unwrap_or_else(|| String::from(GameServer::DEFAULT_NAME));
+                            Self::host(&port, &name).map_err(|e| e.to_string())
+                        },
+                        None => Err(String::from("Enter a port to host on"))
+                    };
+                    
+                    match server {
+                        Ok(server) => self.game = Some(Box::new(server)),
+                        Err(e) => {
+                            *current = MenuVariant::Host { port, name, play: false };
+                            self.ui.notify(&e);
+                        }
+                    }
+                },
+                (MenuVariant::Host { .. }, MenuVariant::Host { port, name, play: true }) => {
+                    // The host joins its own server like any other player, without going through the network
+                    let solo = match (Self::filled(port.clone()), Self::filled(name.clone())) {
+                        (Some(port), Some(name)) => Self::host(&port, &format!("{name}'s game"))
+                            .map_err(|e| e.to_string())
+                            .and_then(|server| Solo::with_server(server, &name).map_err(|e| e.to_string())),
+                        _ => Err(String::from("Enter a port and a name to host and play"))
+                    };
+                    
+                    match solo {
+                        Ok(solo) => {
+                            self.game = Some(Box::new(solo));
+                            *current = MenuVariant::InGame;
+                        },
+                        Err(e) => {
+                            // What was typed stays in the fields, so it must stay in the menu too
+                            *current = MenuVariant::Host { port, name, play: false };
+                            self.ui.notify(&e);
+                        }
+                    }
                 },
                 (MenuVariant::InGame, MenuVariant::InGame) => {},
                 (MenuVariant::InGame, _) => { self.game = None },
@@ -61,5 +113,27 @@ impl Application {
             
             next_frame().await;
         }
-    }    
+    }
+    
+    /// The value of a text field, if anything was typed in it
+    fn filled(field: Option<String>) -> Option<String> {
+        field.filter(|value| !value.trim().is_empty())
+    }
+    
+    /// A server hosted from the menu, announced on the local network as `name`
+    fn host(port: &str, name: &str) -> io::Result<GameServer> {
+        let bans = Bans::load(GameServer::BAN_FILE).unwrap_or_else(|e| {
+            eprintln!("DEBUG: Could not read the bans from {}: {e}", GameServer::BAN_FILE);
+            Bans::default()
+        });
+        let server = GameServer::new(&format!("0.0.0.0:{port}"))?.with_name(name.to_string()).with_bans(bans);
+        
+        match Beacon::new() {
+            Ok(beacon) => Ok(server.with_beacon(beacon)),
+            Err(e) => {
+                eprintln!("DEBUG: Not announcing the server on the local network: {e}");
+                Ok(server)
+            }
+        }
+    }
 }
\ No newline at end of file
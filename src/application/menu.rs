@@ -2,13 +2,16 @@ use desi_ui::*;
 use uilang::uilang;
 use macroquad::prelude::*;
 
+use crate::network::Protocol;
+use crate::network::discovery::Scanner;
 use crate::utils::DiscriminantMap;
 
 #[derive(PartialEq, Eq, Hash, Clone, Debug)]
 pub enum MenuVariant {
     Main,
     Join { name: Option<String>, ip: Option<String>, port: Option<String> },
-    Host { port: Option<String> },
+    /// `play` is set once the host asked to join its own game
+    Host { port: Option<String>, name: Option<String>, play: bool },
     InGame,
     ConfirmQuit,
     Oblivion
@@ -17,6 +20,11 @@ pub enum MenuVariant {
 pub struct Ui {
     data: DiscriminantMap<MenuVariant, Widget>,
     current: MenuVariant,
+    notice: Option<String>,
+    /// Listens to the servers of the local network while the Join menu is shown
+    scanner: Option<Scanner>,
+    /// Why the local network cannot be listened to
+    scan_error: Option<String>,
     terminated: bool
 }
 
@@ -24,11 +32,13 @@ impl Ui {
     const ACTIVATED_MENUS: [MenuVariant; 6] = [
         MenuVariant::Main,
         MenuVariant::Join { name: None, ip: None, port: None },
-        MenuVariant::Host { port: None },
+        MenuVariant::Host { port: None, name: None, play: false },
         MenuVariant::InGame,
         MenuVariant::ConfirmQuit,
         MenuVariant::Oblivion
     ];
+    /// Height of a discovered server in the Join menu's list
+    const SERVER_ROW: f32 = 40.0;
     
     pub fn is_terminated(&self) -> bool {
         self.terminated
@@ -40,8 +50,16 @@ impl Ui {
         &mut self.current
     }
     
+    /// Displays `message` at the bottom of the current menu until another menu is shown
+    pub fn notify(&mut self, message: &str) {
+        self.notice = Some(message.to_string());
+    }
+    
     pub fn switch_menu(&mut self, next: MenuVariant) {
         if let Some(_) = self.data.get(&next) {
+            if std::mem::discriminant(&next) != std::mem::discriminant(&self.current) {
+                self.notice = None;
+            }
             self.current = next;
         } else {
             eprintln!("DEBUG: Tried to switch to an unexisting menu: {next:?}");
@@ -56,6 +74,7 @@ impl Ui {
             self.update();
             self.draw();
             self.check_activations();
+            self.check_discovered();
         }
     }
     
@@ -65,11 +84,38 @@ impl Ui {
                 vec2(screen_width()/2.0, screen_height()/2.0),
                 vec2(screen_width(), screen_height())
             )
-        );    
+        );
+        
+        if let MenuVariant::Join { .. } = self.current {
+            if self.scanner.is_none() && self.scan_error.is_none() {
+                match Scanner::new() {
+                    Ok(scanner) => self.scanner = Some(scanner),
+                    Err(e) => self.scan_error = Some(format!("Cannot look for servers: {e}"))
+                }
+            }
+            if let Some(scanner) = &mut self.scanner {
+                scanner.poll();
+            }
+        } else {
+            self.scanner = None;
+            self.scan_error = None;
+        }
     }
     
     fn draw(&self) {
         self.data[&self.current].draw();
+        self.draw_discovered();
+        
+        if let Some(notice) = &self.notice {
+            let measures = measure_text(notice, None, 24, 1.0);
+            draw_text(
+                notice,
+                (screen_width() - measures.width) / 2.0,
+                screen_height() * 0.95,
+                24.0,
+                RED
+            );
+        }
     }
     
     fn check_activations(&mut self) {
@@ -77,9 +123,89 @@ impl Ui {
         
         activations
             .iter()
-            .for_each( |x|
+            .for_each( |x| {
+                if let ("refresh", Some(scanner)) = (&x.id[..], &mut self.scanner) {
+                    scanner.refresh();
+                }
                 self.switch_menu(self.current.apply(x.clone()))
-            );
+            });
+    }
+    
+    /// Where the Join menu lists the servers of the local network
+    fn discovered_area() -> Rect {
+        Rect::new(screen_width() * 0.02, screen_height() * 0.2, screen_width() * 0.21, screen_height() * 0.58)
+    }
+    
+    /// Index of the discovered server under the mouse
+    fn hovered_server(&self) -> Option<usize> {
+        let scanner = self.scanner.as_ref()?;
+        let area = Self::discovered_area();
+        let mouse = Vec2::from(mouse_position());
+        if !area.contains(mouse) || mouse.y < area.y + Self::SERVER_ROW {
+            return None;
+        }
+        
+        let index = ((mouse.y - area.y) / Self::SERVER_ROW) as usize - 1;
+        (index < scanner.servers().len()).then_some(index)
+    }
+    
+    fn draw_discovered(&self) {
+        if !matches!(self.current, MenuVariant::Join { .. }) {
+            return;
+        }
+        
+        let area = Self::discovered_area();
+        draw_rectangle_lines(area.x, area.y, area.w, area.h, 2.0, DARKGRAY);
+        draw_text("Local servers", area.x + 8.0, area.y + 26.0, 24.0, BLACK);
+        
+        let Some(scanner) = &self.scanner else {
+            let error = self.scan_error.as_deref().unwrap_or_default();
+            draw_text(error, area.x + 8.0, area.y + Self::SERVER_ROW + 20.0, 16.0, RED);
+            return;
+        };
+        
+        if scanner.servers().is_empty() {
+            draw_text("Looking for servers...", area.x + 8.0, area.y + Self::SERVER_ROW + 20.0, 16.0, GRAY);
+        }
+        
+        for (index, server) in scanner.servers().iter().enumerate() {
+            let top = area.y + Self::SERVER_ROW * (index + 1) as f32;
+            if top + Self::SERVER_ROW > area.y + area.h {
+                break;
+            }
+            
+            let announcement = &server.announcement;
+            let compatible = announcement.version == Protocol::VERSION;
+            if compatible && self.hovered_server() == Some(index) {
+                draw_rectangle(area.x, top, area.w, Self::SERVER_ROW, LIGHTGRAY);
+            }
+            
+            let details = if compatible {
+                format!("{}/{} players, map {}, {}", announcement.players, announcement.max_players, announcement.seed, server.address)
+            } else {
+                format!("Protocol version {}, this game speaks {}", announcement.version, Protocol::VERSION)
+            };
+            draw_text(&announcement.name, area.x + 8.0, top + 18.0, 20.0, if compatible { BLACK } else { GRAY });
+            draw_text(&details, area.x + 8.0, top + 34.0, 14.0, if compatible { DARKGRAY } else { GRAY });
+        }
+    }
+    
+    /// Joins the discovered server that is clicked. The press fills the address in, the release joins it.
+    fn check_discovered(&mut self) {
+        let MenuVariant::Join { name, ip, port } = &self.current else { return };
+        let Some(index) = self.hovered_server() else { return };
+        let Some(server) = self.scanner.as_ref().map(|scanner| &scanner.servers()[index]) else { return };
+        if server.announcement.version != Protocol::VERSION {
+            return;
+        }
+        
+        let address = (Some(server.address.ip().to_string()), Some(server.address.port().to_string()));
+        if is_mouse_button_pressed(MouseButton::Left) {
+            // The text inputs were activated by the same click, the name typed is kept
+            self.current = MenuVariant::Join { name: name.clone(), ip: address.0, port: address.1 };
+        } else if is_mouse_button_released(MouseButton::Left) && (ip, port) == (&address.0, &address.1) {
+            self.switch_menu(MenuVariant::InGame);
+        }
     }
 }
 
@@ -92,6 +218,9 @@ impl Default for Ui {
         Self {
             data,
             current: Self::ACTIVATED_MENUS[0].clone(),
+            notice: None,
+            scanner: None,
+            scan_error: None,
             terminated: false
         }
     }
@@ -113,8 +242,9 @@ impl MenuVariant {
     pub fn apply(&self, activation: Activation) -> Self {
         match self {
             Self::Main => match &activation.id[..]  {
+                "solo" => Self::InGame,
                 "join" => Self::Join { name: None, ip: None, port: None } ,
-                "host" => Self::Host { port: None },
+                "host" => Self::Host { port: None, name: None, play: false },
                 "quit" => Self::ConfirmQuit,
                 _      => Self::Main
             },
@@ -132,12 +262,15 @@ impl MenuVariant {
                 "join" => {
                     Self::InGame
                 },
+                "refresh" => self.clone(),
                 _ => { todo!() }
             },
-            Self::Host { .. } => match &activation.id[..] {
+            Self::Host { port, name, .. } => match &activation.id[..] {
                 "back" => Self::Main,
                 "start" => Self::InGame,
-                "port" => Self::Host { port: Some(activation.message.unwrap()) },
+                "play" => Self::Host { port: port.clone(), name: name.clone(), play: true },
+                "port" => Self::Host { port: Some(activation.message.unwrap()), name: name.clone(), play: false },
+                "name" => Self::Host { port: port.clone(), name: Some(activation.message.unwrap()), play: false },
                 _ => todo!()
             },
             Self::InGame => match &activation.id[..] {
@@ -161,28 +294,36 @@ impl MenuVariant {
                     secondary: "BLACK"
                     outline: "2.0"
                     scale: "(0.5, 0.8)"
+                    <Button>
+                        id: "solo"
+                        primary: "GRAY"
+                        secondary: "DARKGRAY"
+                        center: "(0.0, -0.3)"
+                        scale: "(0.4, 0.15)"
+                        <Label> text: "Solo" </Label>
+                    </Button>
                     <Button>
                         id: "join"
                         primary: "GRAY"
                         secondary: "DARKGRAY"
-                        center: "(0.0, -0.26)"
-                        scale: "(0.4, 0.2)"
+                        center: "(0.0, -0.1)"
+                        scale: "(0.4, 0.15)"
                         <Label> text: "Join" </Label>
                     </Button>
                     <Button>
                         id: "host"
                         primary: "GRAY"
                         secondary: "DARKGRAY"
-                        center: "(0.0, 0.0)"
-                        scale: "(0.4, 0.2)"
+                        center: "(0.0, 0.1)"
+                        scale: "(0.4, 0.15)"
                         <Label> text: "Host" </Label>
                     </Button>
                     <Button>
                         id: "quit"
                         primary: "GRAY"
                         secondary: "DARKGRAY"
-                        center: "(0.0, 0.26)"
-                        scale: "(0.4, 0.2)"
+                        center: "(0.0, 0.3)"
+                        scale: "(0.4, 0.15)"
                         <Label> text: "Quit" </Label>
                     </Button>
                 </Frame>
@@ -220,6 +361,16 @@ impl MenuVariant {
                     center: "(0.0, 0.15)"
                     scale: "(0.5, 0.1)"
                 </TextInput>
+                <Button>
+                    id: "refresh"
+                    center: "(-0.385, 0.33)"
+                    scale: "(0.12, 0.06)"
+                    primary: "GRAY"
+                    secondary: "DARKGRAY"
+                    <Label>
+                        text: "Refresh"
+                    </Label>
+                </Button>
                 <Button>
                     id: "join"
                     center: "(0.0, 0.35)"
@@ -253,16 +404,32 @@ impl MenuVariant {
                     primary: "WHITE"
                     secondary: "BLACK"
                     placeholder: "53000"
+                    center: "(0.0, -0.2)"
+                    scale: "(0.4, 0.1)"
+                </TextInput>
+                <TextInput>
+                    id: "name"
+                    primary: "WHITE"
+                    secondary: "BLACK"
+                    placeholder: "Host"
                     center: "(0.0, 0.0)"
                     scale: "(0.4, 0.1)"
                 </TextInput>
+                <Button>
+                    id: "play"
+                    center: "(-0.12, 0.2)"
+                    scale: "(0.2, 0.15)"
+                    primary: "GRAY"
+                    secondary: "DARKGRAY"
+                    <Label> text: "Host and play" </Label>
+                </Button>
                 <Button>
                     id: "start"
-                    center: "(0.0, 0.2)"
-                    scale: "(0.2, 0.2)"
+                    center: "(0.12, 0.2)"
+                    scale: "(0.2, 0.15)"
                     primary: "GRAY"
                     secondary: "DARKGRAY"
-                    <Label> text: "Start server" </Label>
+                    <Label> text: "Server only" </Label>
                 </Button>
                 <Button>
                     id: "back"
@@ -282,7 +449,6 @@ impl MenuVariant {
         uilang!(
             <Frame>
                 primary: "Color::from_rgba(0, 0, 0, 0)"
-                <Label> text: "Work in progress..." primary: "Color::from_rgba(255, 255, 255, 50)" </Label>
                 <Button>
                     id: "back"
                     center: "(0.4, -0.4)"
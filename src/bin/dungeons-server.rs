@@ -0,0 +1,178 @@
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::time::Duration;
+
+use bored::game::map::Map;
+use bored::network::Protocol;
+use bored::network::bans::Bans;
+use bored::network::console;
+use bored::network::discovery::Beacon;
+use bored::network::server::{ GameServer, Scheduling };
+use bored::utils::{ Dynamic, Interruption, Random };
+
+const USAGE: &str = "\
+Usage: dungeons-server --port <PORT> [OPTIONS]
+
+Options:
+  --bind <ADDRESS>             Address to listen on [default: 0.0.0.0]
+  --port <PORT>                Port to listen on
+  --name <NAME>                Name shown to the players of the local network [default: Unnamed server]
+  --no-beacon                  Do not announce the server on the local network
+  --seed <SEED>                Seed of the map [default: random]
+  --map-size <WIDTH>x<HEIGHT>  Size of the map, in rooms [default: 50x50]
+  --max-players <COUNT>        Players allowed at once [default: 8]
+  --max-per-ip <COUNT>         Connections one address may have open at once [default: 4]
+  --idle-timeout <SECONDS>     Time after which a silent client is disconnected [default: 10]
+  --bans <FILE>                File keeping the banned names [default: bans.txt]
+  --thread-per-client          Serve each client from its own thread rather than from a single event loop
+  --help                       Print this message
+
+Commands typed while the server runs are handled by its console, type help for the list.";
+
+/// What the server is started with, see `USAGE`
+struct Options {
+    bind: String,
+    port: u16,
+    name: String,
+    beacon: bool,
+    seed: Option<usize>,
+    map_size: (usize, usize),
+    max_players: usize,
+    max_per_ip: usize,
+    idle_timeout: Duration,
+    bans: String,
+    scheduling: Scheduling
+}
+
+impl Options {
+    /// `None` if the usage was asked for
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Option<Self>, String> {
+        let mut options = Self {
+            bind: String::from("0.0.0.0"),
+            port: 0,
+            name: String::from(GameServer::DEFAULT_NAME),
+            beacon: true,
+            seed: None,
+            map_size: (GameServer::DEFAULT_MAP_WIDTH, GameServer::DEFAULT_MAP_HEIGHT),
+            max_players: GameServer::DEFAULT_MAX_PLAYERS,
+            max_per_ip: GameServer::DEFAULT_MAX_CONNECTIONS_PER_IP,
+            idle_timeout: Protocol::DEFAULT_IDLE_TIMEOUT,
+            bans: String::from(GameServer::BAN_FILE),
+            scheduling: Scheduling::EventLoop
+        };
+        let mut port = None;
+        
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--bind" => options.bind = Self::value(&arg, args.next())?,
+                "--port" => port = Some(Self::value(&arg, args.next())?),
+                "--name" => options.name = Self::value(&arg, args.next())?,
+                "--no-beacon" => options.beacon = false,
+                "--seed" => options.seed = Some(Self::value(&arg, args.next())?),
+                "--map-size" => options.map_size = Self::map_size(&Self::value::<String>(&arg, args.next())?)?,
+                "--max-players" => options.max_players = Self::value(&arg, args.next())?,
+                "--max-per-ip" => options.max_per_ip = Self::value(&arg, args.next())?,
+                "--idle-timeout" => options.idle_timeout = Self::idle_timeout(&Self::value::<String>(&arg, args.next())?)?,
+                "--bans" => options.bans = Self::value(&arg, args.next())?,
+                "--thread-per-client" => options.scheduling = Scheduling::ThreadPerClient,
+                "--help" | "-h" => return Ok(None),
+                _ => return Err(format!("unexpected argument '{arg}'"))
+            }
+        }
+        
+        options.port = port.ok_or("the port is required")?;
+        Ok(Some(options))
+    }
+    
+    fn value<T: FromStr>(name: &str, value: Option<String>) -> Result<T, String> {
+        let value = value.ok_or(format!("{name} needs a value"))?;
+        value.parse().map_err(|_| format!("invalid value '{value}' for {name}"))
+    }
+    
+    fn idle_timeout(value: &str) -> Result<Duration, String> {
+        value.parse()
+            .ok()
+            .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+            .filter(|timeout| !timeout.is_zero())
+            .ok_or(format!("invalid idle timeout '{value}', expected a positive number of seconds"))
+    }
+    
+    fn map_size(value: &str) -> Result<(usize, usize), String> {
+        let invalid = || format!("invalid map size '{value}', expected <WIDTH>x<HEIGHT> with sides from 1 to {}", Map::MAX_SIDE);
+        
+        let (width, height) = value.split_once('x').ok_or_else(invalid)?;
+        let (width, height) = (width.parse().map_err(|_| invalid())?, height.parse().map_err(|_| invalid())?);
+        if !(1..=Map::MAX_SIDE).contains(&width) || !(1..=Map::MAX_SIDE).contains(&height) {
+            return Err(invalid());
+        }
+        
+        Ok((width, height))
+    }
+}
+
+fn main() -> ExitCode {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+        },
+        Err(e) => {
+            eprintln!("error: {e}\n\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+    
+    let bans = match Bans::load(&options.bans) {
+        Ok(bans) => bans,
+        Err(e) => {
+            eprintln!("error: could not read the bans from {}: {e}", options.bans);
+            return ExitCode::FAILURE;
+        }
+    };
+    
+    Random::seed();
+    let (width, height) = options.map_size;
+    let seed = options.seed.unwrap_or_else(Random::any);
+    
+    let server = GameServer::new(&format!("{}:{}", options.bind, options.port));
+    let mut server = match server {
+        Ok(server) => server
+            .with_name(options.name)
+            .with_scheduling(options.scheduling)
+            .with_max_players(options.max_players)
+            .with_max_connections_per_ip(options.max_per_ip)
+            .with_idle_timeout(options.idle_timeout)
+            .with_bans(bans)
+            .with_map(seed, width, height),
+        Err(e) => {
+            eprintln!("error: could not listen on {}:{}: {e}", options.bind, options.port);
+            return ExitCode::FAILURE;
+        }
+    };
+    
+    if options.beacon {
+        match Beacon::new() {
+            Ok(beacon) => server = server.with_beacon(beacon),
+            Err(e) => GameServer::log(&format!("Not announcing the server on the local network: {e}"))
+        }
+    }
+    
+    GameServer::log(&format!("Map {seed} of {width}x{height} rooms, up to {} players", options.max_players));
+    
+    let commands = console::stdin_lines();
+    Interruption::catch();
+    while !Interruption::requested() && !server.is_stopped() {
+        server.update();
+        server.wait();
+        
+        while let Ok(command) = commands.try_recv() {
+            println!("{}", server.execute(&command));
+        }
+    }
+    
+    if !server.is_stopped() {
+        server.shutdown();
+    }
+    ExitCode::SUCCESS
+}
@@ -81,13 +81,21 @@ impl From<GameObject> for GameComponent {
 
 impl GameComponent {
     with!{ body: Body }
+    with!{ controller: Controller }
     
-    pub fn slide(&mut self, map: &Map) {
-        let movement = self.controller.get_movement(self.body.position, map);
+    pub fn slide(&mut self, map: &Map, dt: f32) {
+        let movement = self.controller.get_movement(self.body.position, map, dt);
         
         self.body.impulse(movement.velocity);
     }
     
+    /// Runs one simulation step of `dt` seconds against the walls surrounding the component
+    pub fn step(&mut self, map: &Map, dt: f32) {
+        self.slide(map, dt);
+        self.collisions(map.components_around(self.body.position), false);
+        self.update();
+    }
+    
     pub fn collisions<'a>(&mut self, others: impl Iterator<Item=&'a GameComponent>, only_check: bool) -> Vec<Collider<'a>> {
         let mut collided_with = Vec::<Collider>::new();
         
@@ -1,6 +1,6 @@
 use macroquad::prelude::*;
 
-use super::{component::GameComponent, keys::KeyBinding, map::Map};
+use super::{component::GameComponent, keys::{ KeyBinding, PlayerInput }, map::Map};
 
 #[derive(Copy, Clone, Debug, Default)]
 pub struct Movement {
@@ -8,14 +8,34 @@ pub struct Movement {
     pub orientation: Vec2
 }
 
+impl Movement {
+    pub fn from_input(input: &PlayerInput, speed: f32, dt: f32) -> Self {
+        Self {
+            velocity: input.slide * speed * dt,
+            orientation: input.look
+        }
+    }
+}
+
 
 #[derive(Debug, Clone)]
 pub enum Controller {
     Player { controls: KeyBinding, speed: f32 },
+    /// A player driven by the inputs it sends over the network
+    Remote { input: PlayerInput, speed: f32 },
     Monster,
     BrainDead,
 }
 
+/// What drives a component, as far as others need to know
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ControllerKind {
+    /// Moved by a player, whether locally or over the network
+    Player,
+    Monster,
+    BrainDead
+}
+
 impl Default for Controller {
     fn default() -> Self {
         Self::Player {
@@ -26,12 +46,14 @@ impl Default for Controller {
 }
 
 impl Controller {
-    pub fn get_movement(&mut self, from: Vec2, world: &Map) -> Movement {
+    pub fn get_movement(&mut self, from: Vec2, world: &Map, dt: f32) -> Movement {
         let mut movement = Movement::default();
         match self {
             Self::Player { controls, speed } => {
-                movement.velocity = controls.slide.get_vec() * *speed * get_frame_time();
-                movement.orientation = controls.look.get_vec();
+                movement = Movement::from_input(&controls.poll(), *speed, dt);
+            },
+            Self::Remote { input, speed } => {
+                movement = Movement::from_input(input, *speed, dt);
             },
             Self::Monster => {
                 movement.velocity = vec2(10.0, 0.0);
@@ -41,4 +63,20 @@ impl Controller {
         
         movement
     }
+    
+    pub fn kind(&self) -> ControllerKind {
+        match self {
+            Self::Player { .. } | Self::Remote { .. } => ControllerKind::Player,
+            Self::Monster => ControllerKind::Monster,
+            Self::BrainDead => ControllerKind::BrainDead
+        }
+    }
+    
+    /// Hands a local player over to network inputs, keeping its speed
+    pub fn into_remote(self) -> Self {
+        match self {
+            Self::Player { speed, .. } => Self::Remote { input: PlayerInput::default(), speed },
+            other => other
+        }
+    }
 }
@@ -29,6 +29,27 @@ impl DirectionKeys {
     }
 }
 
+/// What a player asks its character to do, independently of the keys that were pressed
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct PlayerInput {
+    pub slide: Vec2,
+    pub look: Vec2,
+    pub action: bool
+}
+
+impl PlayerInput {
+    /// Inputs coming from the network may be forged: directions are clamped to unit length
+    pub fn sanitized(self) -> Self {
+        let clamp = |v: Vec2| if v.is_finite() { v.clamp_length_max(1.0) } else { Vec2::ZERO };
+        
+        Self {
+            slide: clamp(self.slide),
+            look: clamp(self.look),
+            action: self.action
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct KeyBinding {
     pub slide: DirectionKeys,
@@ -55,4 +76,14 @@ impl Default for KeyBinding {
             action: KeyCode::Space
         }
     }
-}
\ No newline at end of file
+}
+
+impl KeyBinding {
+    pub fn poll(&self) -> PlayerInput {
+        PlayerInput {
+            slide: self.slide.get_vec(),
+            look: self.look.get_vec(),
+            action: is_key_down(self.action)
+        }
+    }
+}
@@ -9,7 +9,6 @@ use super::body::Body;
 use super::controller::Controller;
 use super::keys::KeyBinding;
 
-use crate::network::server::GameServer;
 use crate::utils::Random;
 use crate::utils::srand;
 
@@ -107,7 +106,11 @@ impl AccessFlag {
 
 #[derive(Debug, Default)]
 pub struct Map {
-    pub rooms: Vec<Vec<Chunk>>
+    pub rooms: Vec<Vec<Chunk>>,
+    /// Rooms per line
+    pub width: usize,
+    /// Lines of rooms
+    pub height: usize
 }
 
 #[derive(Clone, Debug)]
@@ -118,6 +121,8 @@ pub enum Chunk {
 }
 
 impl Map {
+    /// Largest width and height a map can be generated with
+    pub const MAX_SIDE: usize = 1000;
     
     pub fn get_rooms_iterator(&self) -> impl Iterator<Item = &Room> {
         self.rooms
@@ -132,6 +137,39 @@ impl Map {
             )
     }
     
+    /// Grid cell `(line, column)` of the room containing `position`
+    pub fn cell_of(&self, position: Vec2) -> (i32, i32) {
+        (
+            (position.y / Room::HEIGHT).floor() as i32 + self.height as i32 / 2,
+            (position.x / Room::WIDTH).floor() as i32 + self.width as i32 / 2
+        )
+    }
+    
+    pub fn get_room(&self, line: i32, column: i32) -> Option<&Room> {
+        let line = self.rooms.get(usize::try_from(line).ok()?)?;
+        
+        if let Chunk::Generated(room) = line.get(usize::try_from(column).ok()?)? {
+            Some(room)
+        } else {
+            None
+        }
+    }
+    
+    /// Walls of the room containing `position` and of the 8 rooms around it
+    pub fn components_around(&self, position: Vec2) -> impl Iterator<Item = &GameComponent> {
+        let (line, column) = self.cell_of(position);
+        
+        (line - 1..=line + 1)
+            .flat_map(move |l| (column - 1..=column + 1).map(move |c| (l, c)))
+            .filter_map(|(l, c)| self.get_room(l, c))
+            .flat_map(|room| room.components.iter().flatten())
+    }
+    
+    /// Where new players appear: the middle of the central crossroads
+    pub fn spawn_point() -> Vec2 {
+        vec2(Room::WIDTH, Room::HEIGHT) / 2.0 - Body::default().size / 2.0
+    }
+    
     pub fn generate(max_width: usize, max_height: usize, seed: usize) -> Self {
         
         unsafe { srand(seed); }
@@ -173,8 +211,8 @@ impl Map {
                     
                     room_matrix[cursor.0][cursor.1] = Chunk::Generated(
                         new_room.with_indices(
-                            cursor.0 as i32 - GameServer::MAP_HEIGHT as i32 / 2, 
-                            cursor.1 as i32 - GameServer::MAP_WIDTH as i32 / 2
+                            cursor.0 as i32 - max_height as i32 / 2, 
+                            cursor.1 as i32 - max_width as i32 / 2
                         )
                     );             
                 },
@@ -183,7 +221,9 @@ impl Map {
         }
                 
         Map {
-            rooms: room_matrix
+            rooms: room_matrix,
+            width: max_width,
+            height: max_height
         }
     }
 }
@@ -1,6 +1,6 @@
 use crate::utils::Drawable;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum GameObject {
     Player,
     Monster,
@@ -0,0 +1,4 @@
+pub mod utils;
+pub mod network;
+pub mod game;
+pub mod application;
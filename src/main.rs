@@ -1,16 +1,11 @@
 use std::collections::VecDeque;
 
-use application::Application;
+use bored::application::Application;
 
 use macroquad::prelude::*;
-use utils::{Drawable, Random};
+use bored::utils::{Drawable, Random};
 
-mod utils;
-mod network;
-mod game;
-mod application;
-
-use game::map::{ Map, Chunk };
+use bored::game::map::{ Map, Chunk };
 
 #[macroquad::main("Bored")]
 async fn main() {    
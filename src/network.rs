@@ -1,228 +1,918 @@
 use macroquad::prelude::*;
 
-use std::str::FromStr;
-use std::net::TcpStream;
-use std::io::{ BufRead, BufReader, ErrorKind, Read, Write };
+use std::fmt;
+use std::io::{ ErrorKind, Read, Write };
+use std::time::{ Duration, Instant };
 
+use auto_with::with;
+
+use crate::game::keys::PlayerInput;
 use crate::utils::{ Dynamic, Drawable, Controlable };
 
+use codec::{ Reader, Shareable };
+use sequence::Sequence;
+use rate::TokenBucket;
+use replication::{ Entity, EntityDelta };
+use snapshot::Snapshot;
+
 pub mod client;
 pub mod server;
+pub mod codec;
+pub mod prediction;
+pub mod interpolation;
+pub mod sequence;
+pub mod datagram;
+pub mod connection;
+pub mod transport;
+pub mod outbox;
+pub mod readiness;
+pub mod solo;
+pub mod console;
+pub mod bans;
+pub mod dashboard;
+pub mod discovery;
+pub mod chat;
+pub mod rate;
+pub mod replication;
+pub mod snapshot;
+pub mod area;
 
 pub trait GameAgent : Dynamic + Drawable + Controlable {}
 
-#[derive(Copy, Clone, Debug)]
-pub struct ShareableType<T> {
-    value: T
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FormatError {
+    EmptyMessage,
+    MissingField,
+    WrongType,
+    InvalidValue,
+    ByteAfterEnd,
+    Truncated { expected: usize, available: usize },
+    InvalidText
 }
 
-impl <T: FromStr> ShareableType<T> {
-    pub const SEPARATOR: u8 = 47; // Slash
-    
-    pub fn parse<'a>(i: &mut impl Iterator<Item=&'a u8>) -> Result<T, FormatError> {
-        if let Ok(v) = Self::extract_next_field(i).parse::<T>() {
-            Ok(v)
-        } else {
-            Err(FormatError::InvalidValue)
-        }
-    }
-    
-    fn extract_next_field<'a>(i: &mut impl Iterator<Item=&'a u8>) -> String {
-        let mut buffer = String::new();
-        
-        loop {
-            let s = *i.next().unwrap_or(&0);
-            
-            if s == Self::SEPARATOR || s == 0 {
-                break;
-            } else {
-                buffer.push(s as char);
-            }
+/// Why a server refused a `Command::Hello`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RejectionReason {
+    VersionMismatch { server: u16 },
+    ServerFull,
+    NameTaken,
+    Banned,
+    /// The address already has `GameServer::max_connections_per_ip` connections open
+    TooManyConnections
+}
+
+impl fmt::Display for RejectionReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RejectionReason::VersionMismatch { server } => write!(
+                f,
+                "server speaks protocol version {server}, this client speaks version {}",
+                Protocol::VERSION
+            ),
+            RejectionReason::ServerFull => write!(f, "server is full"),
+            RejectionReason::NameTaken => write!(f, "this name is already taken"),
+            RejectionReason::Banned => write!(f, "this name is banned from the server"),
+            RejectionReason::TooManyConnections => write!(f, "too many connections from this address")
         }
-                
-        buffer
     }
 }
 
+/// Why a connection was closed on purpose
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DisconnectionReason {
+    /// The peer sent too many frames or commands that could not be understood
+    Malformed,
+    Shutdown,
+    /// An administrator removed the player from the server
+    Kicked,
+    /// An administrator banned the player's name
+    Banned,
+    /// The peer sent more commands, or larger frames, than it is allowed to
+    Flooding
+}
 
-#[derive(Copy, Clone, Debug)]
-pub enum FormatError {
-    EmptyMessage,
-    MissingField,
-    WrongType,
-    InvalidValue,
-    ByteAfterEnd
+impl fmt::Display for DisconnectionReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DisconnectionReason::Malformed => write!(f, "too many malformed messages"),
+            DisconnectionReason::Shutdown => write!(f, "the server shut down"),
+            DisconnectionReason::Kicked => write!(f, "kicked by the server"),
+            DisconnectionReason::Banned => write!(f, "banned from the server"),
+            DisconnectionReason::Flooding => write!(f, "too many or too large messages")
+        }
+    }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Command {
-    Spawn (usize),
-    Reposition (usize, Vec2),
+    /// An entity appeared, or came to the knowledge of the receiver
+    Spawn (usize, Entity),
+    /// Fields of an entity that changed, as of a given server time in milliseconds
+    Update (usize, u64, EntityDelta),
     Despawn(usize),
     Unknown,
     IllFormated (FormatError),
-    ChangeMap (usize)
+    /// Seed and size in rooms of the map to generate
+    ChangeMap { seed: usize, width: usize, height: usize },
+    /// `session` is the token of the session to resume, if any
+    Hello { version: u16, name: String, build: String, session: Option<u64> },
+    /// Assigned id and session token, to present again when reconnecting
+    Welcome (usize, u64),
+    Rejected (RejectionReason),
+    /// Input a client used for its simulation step number `.0`
+    Input (u32, PlayerInput),
+    /// Sent to a player only: its authoritative state after the input `sequence` was simulated
+    Acknowledge { sequence: u32, position: Vec2, velocity: Vec2 },
+    /// Heartbeats, carrying the sender's clock in milliseconds. Handled by `Protocol` itself.
+    Ping (u64),
+    Pong (u64),
+    /// Port of the UDP socket the server opened for this client, see `Connection`
+    UdpOffer (u16),
+    /// Last command of a connection the sender is closing
+    Disconnect (DisconnectionReason),
+    /// Text from the server, shown to the players
+    SystemMessage (String),
+    /// Something a player said. The server fills `sender` in before relaying it to everyone.
+    Chat { sender: String, text: String },
+    /// The entities a client knows about, as changes since a snapshot it acknowledged
+    Snapshot (Snapshot),
+    /// Sent by clients for every snapshot they applied, the next ones being built on it
+    SnapshotAck (u32)
 }
 
 impl From<&[u8]> for Command {
     fn from(s: &[u8]) -> Self {
-        match s.get(0) {
-            Some(command_id) => {
-                let mut iterator = s.into_iter();
-                iterator.next(); // Ignore command id
-                match command_id {
-                    2 => match ShareableType::<usize>::parse(&mut iterator) {
-                        Ok(id) => Command::Spawn(id),
-                        Err(e) => Command::IllFormated(e)
-                    },
-                    3 => match (ShareableType::<usize>::parse(&mut iterator), ShareableType::<f32>::parse(&mut iterator), ShareableType::<f32>::parse(&mut iterator)) {
-                        (Ok(id), Ok(x), Ok(y)) => Command::Reposition(id, vec2(x, y)),
-                        (Err(e0), _, _) => Command::IllFormated(e0),
-                        (_, Err(e1), _) => Command::IllFormated(e1),
-                        (_, _, Err(e2)) => Command::IllFormated(e2)
-                    },
-                    4 => match ShareableType::<usize>::parse(&mut iterator) {
-                        Ok(id) => Command::Despawn(id),
-                        Err(e) => Command::IllFormated(e)
-                    },
-                    5 => match ShareableType::<usize>::parse(&mut iterator) {
-                        Ok(seed) => Command::ChangeMap(seed),
-                        Err(e) => Command::IllFormated(e)  
-                    },
-                    _ => Command::Unknown
-                }
-            },
-            None => Command::IllFormated (FormatError::EmptyMessage)
-        }
+        Self::decode(s).unwrap_or_else(Command::IllFormated)
     }
 }
 
 impl Command {
-    pub fn as_bytes(&self) -> Vec<u8>{        
-        let separator: u8 = ShareableType::<u8>::SEPARATOR;
-        
-        let (header, body) = match self {
-            Command::Spawn(id) => (2u8, Vec::from(id.to_string().as_bytes())),
-            Command::Reposition(id, pos) => (
-                3,
-                id
-                    .to_string()
-                    .as_bytes()
-                    .into_iter()
-                    .chain(&[separator])
-                    .chain(
-                        pos.x
-                            .to_string()
-                            .as_bytes()
-                    )
-                    .chain(&[separator])
-                    .chain(pos.y.to_string().as_bytes())
-                    .map(|x| *x)
-                    .collect()
-            ),
-            Command::Despawn(id) => (
-                4,
-                id
-                    .to_string()
-                    .as_bytes()
-                    .into_iter()
-                    .map(|x| *x)
-                    .collect()
-            ),
-            Command::ChangeMap(seed) => (5, Vec::from(seed.to_string().as_bytes())),
-            
-            Command::IllFormated(_) => (6, Vec::new()),
-            Command::Unknown => (7, Vec::new())
+    pub fn decode(s: &[u8]) -> Result<Self, FormatError> {
+        let (header, body) = s.split_first().ok_or(FormatError::EmptyMessage)?;
+        let mut reader = Reader::new(body);
+        
+        let command = match header {
+            2 => Command::Spawn(reader.read()?, reader.read()?),
+            3 => Command::Update(reader.read()?, reader.read()?, reader.read()?),
+            4 => Command::Despawn(reader.read()?),
+            5 => Command::ChangeMap { seed: reader.read()?, width: reader.read()?, height: reader.read()? },
+            6 => Command::IllFormated(reader.read()?),
+            8 => Command::Hello { version: reader.read()?, name: reader.read()?, build: reader.read()?, session: reader.read()? },
+            9 => Command::Welcome(reader.read()?, reader.read()?),
+            10 => Command::Rejected(reader.read()?),
+            11 => Command::Input(reader.read()?, reader.read()?),
+            12 => Command::Acknowledge { sequence: reader.read()?, position: reader.read()?, velocity: reader.read()? },
+            13 => Command::Ping(reader.read()?),
+            14 => Command::Pong(reader.read()?),
+            15 => Command::UdpOffer(reader.read()?),
+            16 => Command::Disconnect(reader.read()?),
+            17 => Command::SystemMessage(reader.read()?),
+            18 => Command::Chat { sender: reader.read()?, text: reader.read()? },
+            19 => Command::Snapshot(reader.read()?),
+            20 => Command::SnapshotAck(reader.read()?),
+            _ => return Ok(Command::Unknown)
         };
         
-        [header].into_iter().chain(body.into_iter()).collect::<Vec<u8>>() 
+        reader.finish()?;
+        Ok(command)
+    }
+    
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        
+        match self {
+            Command::Spawn(id, entity) => {
+                2u8.encode(&mut bytes);
+                id.encode(&mut bytes);
+                entity.encode(&mut bytes);
+            },
+            Command::Update(id, time, delta) => {
+                3u8.encode(&mut bytes);
+                id.encode(&mut bytes);
+                time.encode(&mut bytes);
+                delta.encode(&mut bytes);
+            },
+            Command::Despawn(id) => {
+                4u8.encode(&mut bytes);
+                id.encode(&mut bytes);
+            },
+            Command::ChangeMap { seed, width, height } => {
+                5u8.encode(&mut bytes);
+                seed.encode(&mut bytes);
+                width.encode(&mut bytes);
+                height.encode(&mut bytes);
+            },
+            Command::IllFormated(e) => {
+                6u8.encode(&mut bytes);
+                e.encode(&mut bytes);
+            },
+            Command::Unknown => 7u8.encode(&mut bytes),
+            Command::Hello { version, name, build, session } => {
+                8u8.encode(&mut bytes);
+                version.encode(&mut bytes);
+                name.encode(&mut bytes);
+                build.encode(&mut bytes);
+                session.encode(&mut bytes);
+            },
+            Command::Welcome(id, session) => {
+                9u8.encode(&mut bytes);
+                id.encode(&mut bytes);
+                session.encode(&mut bytes);
+            },
+            Command::Rejected(reason) => {
+                10u8.encode(&mut bytes);
+                reason.encode(&mut bytes);
+            },
+            Command::Input(sequence, input) => {
+                11u8.encode(&mut bytes);
+                sequence.encode(&mut bytes);
+                input.encode(&mut bytes);
+            },
+            Command::Acknowledge { sequence, position, velocity } => {
+                12u8.encode(&mut bytes);
+                sequence.encode(&mut bytes);
+                position.encode(&mut bytes);
+                velocity.encode(&mut bytes);
+            },
+            Command::Ping(time) => {
+                13u8.encode(&mut bytes);
+                time.encode(&mut bytes);
+            },
+            Command::Pong(time) => {
+                14u8.encode(&mut bytes);
+                time.encode(&mut bytes);
+            },
+            Command::UdpOffer(port) => {
+                15u8.encode(&mut bytes);
+                port.encode(&mut bytes);
+            },
+            Command::Disconnect(reason) => {
+                16u8.encode(&mut bytes);
+                reason.encode(&mut bytes);
+            },
+            Command::SystemMessage(text) => {
+                17u8.encode(&mut bytes);
+                text.encode(&mut bytes);
+            },
+            Command::Chat { sender, text } => {
+                18u8.encode(&mut bytes);
+                sender.encode(&mut bytes);
+                text.encode(&mut bytes);
+            },
+            Command::Snapshot(snapshot) => {
+                19u8.encode(&mut bytes);
+                snapshot.encode(&mut bytes);
+            },
+            Command::SnapshotAck(tick) => {
+                20u8.encode(&mut bytes);
+                tick.encode(&mut bytes);
+            }
+        }
+        
+        bytes
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum ProtocolError {
     Disconnection,
+    Pending,
     WrongSequence,
     OutdatedPackage,
-    IllFormatedSequenceNumber
+    IllFormatedSequenceNumber,
+    /// A frame longer than `Protocol::max_frame_length` was announced, the connection cannot go on
+    FrameTooLarge,
+    /// The peer pinged faster than `Protocol` lets it, the ping was not answered
+    TooManyPings
+}
+
+/// Traffic counters of a `Protocol`
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ProtocolStats {
+    pub sent: u64,
+    pub received: u64,
+    /// Frames the peer sent that never arrived, according to the gaps between sequence numbers
+    pub lost: u64,
+    /// Frames that were not newer than the last one received, and were dropped
+    pub duplicated: u64,
+    /// Frames sent again because the peer did not acknowledge them in time
+    pub resent: u64,
+    /// Frames and commands received that could not be understood
+    pub malformed: u64,
+    /// Bytes written to and read from the transport, headers included
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Smoothed round trip time, once a first `Command::Pong` was received
+    pub rtt: Option<Duration>,
+    /// Mean deviation of the round trip time
+    pub jitter: Duration
+}
+
+impl ProtocolStats {
+    /// Smoothes round trip times the way TCP does (RFC 6298)
+    fn observe_rtt(&mut self, sample: Duration) {
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => {
+                self.jitter = (self.jitter * 3 + rtt.abs_diff(sample)) / 4;
+                (rtt * 7 + sample) / 8
+            },
+            None => {
+                self.jitter = sample / 2;
+                sample
+            }
+        });
+    }
 }
 
+/// Frames are laid out as `[length: u32][sequence: u32][acknowledgement: u32][command]`,
+/// every field being little-endian. `length` counts everything after itself and
+/// `acknowledgement` is the sequence of the last frame received from the peer.
+/// Sequences start at 1, an acknowledgement of 0 meaning nothing was received yet.
+///
+/// Nothing marks where a frame starts: a header whose length or sequence cannot be right means
+/// the stream is corrupted, and bytes are skipped until one that could be is found.
 #[derive(Debug)]
 pub struct Protocol {
-    last_reception: [u8; 4],
-    last_send: [u8; 4]
+    last_reception: Sequence,
+    last_send: Sequence,
+    acknowledged: Sequence,
+    stats: ProtocolStats,
+    incoming: Vec<u8>,
+    /// Frames the stream could not take yet, as it is non-blocking
+    outgoing: Vec<u8>,
+    
+    created: Instant,
+    last_heard: Instant,
+    last_ping: Option<Instant>,
+    pongs_due: Vec<u64>,
+    /// Limits the pings of the peer, each of them costing a pong
+    pings: TokenBucket,
+    ping_interval: Duration,
+    idle_timeout: Duration,
+    /// Longest frame the peer may send, up to `Protocol::MAX_FRAME_LENGTH`
+    max_frame_length: usize
+}
+
+impl Default for Protocol {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Protocol {
+    /// Bumped whenever the meaning of frames or commands changes
+    pub const VERSION: u16 = 11;
+    
+    pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(1);
+    pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+    /// Pings a peer may send at once, and per second after that. Peers ping once per `DEFAULT_PING_INTERVAL`.
+    const PING_BURST: u32 = 5;
+    const PING_RATE: f32 = 2.0;
+    
+    const LENGTH_SIZE: usize = 4;
+    const HEADER_SIZE: usize = 8;
+    /// Frames further than this from the last one received are taken for garbage, a stream never loses any
+    const MAX_SEQUENCE_GAP: u32 = 64;
+    /// Longer frames are taken for garbage
+    pub const MAX_FRAME_LENGTH: usize = 1 << 16;
+    
     pub fn new() -> Self {
         Self {
-            last_reception: [0; 4],
-            last_send: [0; 4]
-        }
-    }
-    
-    pub fn reception(&mut self, stream: &mut TcpStream) -> Result<Command, ProtocolError> {
-        let mut timestamp_buffer = [0u8;4];
-        let mut body_buffer = Vec::<u8>::new();
-        let mut reader = BufReader::new(stream);
-        match (reader.skip_until(1), reader.read_exact(&mut timestamp_buffer), reader.read_until(0, &mut body_buffer)) {   
-            (Ok(_), Ok(_), Ok(_)) => 
-                if Self::lower_4_bytes(&self.last_reception, &timestamp_buffer) {
-                    self.last_reception = timestamp_buffer;
-                    if body_buffer.len() == 0 {
-                        return Err(ProtocolError::Disconnection)
-                    } else {   
-                        return Ok(Command::from(&body_buffer[..body_buffer.len()-1]));
-                    }
-                } else {
-                    Err(ProtocolError::OutdatedPackage)
+            last_reception: Sequence::default(),
+            last_send: Sequence::default(),
+            acknowledged: Sequence::default(),
+            stats: ProtocolStats::default(),
+            incoming: Vec::new(),
+            outgoing: Vec::new(),
+            created: Instant::now(),
+            last_heard: Instant::now(),
+            last_ping: None,
+            pongs_due: Vec::new(),
+            pings: TokenBucket::new(Self::PING_BURST, Self::PING_RATE),
+            ping_interval: Self::DEFAULT_PING_INTERVAL,
+            idle_timeout: Self::DEFAULT_IDLE_TIMEOUT,
+            max_frame_length: Self::MAX_FRAME_LENGTH
+        }
+    }
+    
+    with!{ idle_timeout: Duration }
+    with!{ max_frame_length: usize }
+    
+    pub fn stats(&self) -> ProtocolStats {
+        self.stats
+    }
+    
+    /// Number of frames sent that the peer did not acknowledge yet
+    pub fn unacknowledged(&self) -> u32 {
+        self.last_send.distance_from(self.acknowledged)
+    }
+    
+    /// Returns the next complete command, reading as much as needed from `stream`.
+    /// On a non-blocking stream, `ProtocolError::Pending` means no full frame is available yet.
+    /// `ProtocolError::IllFormatedSequenceNumber` means corrupted bytes were skipped, the next call resumes at the following frame.
+    pub fn reception(&mut self, stream: &mut impl Read) -> Result<Command, ProtocolError> {
+        loop {
+            if let Some(frame) = self.next_frame()? {
+                match self.open(&frame)? {
+                    Command::Ping(_) if !self.pings.take() => return Err(ProtocolError::TooManyPings),
+                    Command::Ping(time) => self.pongs_due.push(time),
+                    Command::Pong(time) => self.measure_rtt(time),
+                    command => return Ok(command)
+                }
+                continue;
+            }
+            
+            let mut buffer = [0u8; 1024];
+            match stream.read(&mut buffer) {
+                Ok(0) => return Err(ProtocolError::Disconnection),
+                Ok(n) => {
+                    self.stats.bytes_received += n as u64;
+                    self.incoming.extend_from_slice(&buffer[..n]);
                 },
-            (_, Err(e), _) => {
-                if let ErrorKind::UnexpectedEof = e.kind() {
-                    Err(ProtocolError::Disconnection)
-                } else {
-                    Err(ProtocolError::IllFormatedSequenceNumber)
+                Err(e) => match e.kind() {
+                    ErrorKind::Interrupted => {},
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut => return Err(ProtocolError::Pending),
+                    ErrorKind::UnexpectedEof
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::BrokenPipe => return Err(ProtocolError::Disconnection),
+                    _ => return Err(ProtocolError::WrongSequence)
                 }
+            }
+        }
+    }
+    
+    /// Frames `command` and writes as much as the stream takes, the rest being kept for `flush`
+    pub fn send(&mut self, stream: &mut impl Write, command: Command) -> Result<(), std::io::Error> {
+        let body = command.as_bytes();
+        self.last_send = self.last_send.next();
+        
+        ((Self::HEADER_SIZE + body.len()) as u32).encode(&mut self.outgoing);
+        self.last_send.encode(&mut self.outgoing);
+        self.last_reception.encode(&mut self.outgoing);
+        self.outgoing.extend_from_slice(&body);
+        
+        self.stats.sent += 1;
+        self.flush(stream)
+    }
+    
+    /// Writes the frames the stream could not take earlier
+    pub fn flush(&mut self, stream: &mut impl Write) -> Result<(), std::io::Error> {
+        while !self.outgoing.is_empty() {
+            match stream.write(&self.outgoing) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.stats.bytes_sent += n as u64;
+                    self.outgoing.drain(..n);
+                },
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e)
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Whether some frames are waiting for the stream to accept them
+    pub fn backlogged(&self) -> bool {
+        !self.outgoing.is_empty()
+    }
+    
+    /// Answers the peer's pings, sends ours when they are due, and reports a
+    /// `ProtocolError::Disconnection` once the peer has been silent for longer than the idle timeout.
+    /// Must be called regularly, alongside `reception`.
+    pub fn heartbeat(&mut self, stream: &mut impl Write) -> Result<(), ProtocolError> {
+        if self.last_heard.elapsed() > self.idle_timeout {
+            return Err(ProtocolError::Disconnection);
+        }
+        
+        if let Err(e) = self.flush(stream)
+        && let ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted = e.kind() {
+            return Err(ProtocolError::Disconnection);
+        }
+        
+        let mut outgoing = std::mem::take(&mut self.pongs_due)
+            .into_iter()
+            .map(Command::Pong)
+            .collect::<Vec<_>>();
+        
+        if self.last_ping.is_none_or(|last| last.elapsed() >= self.ping_interval) {
+            self.last_ping = Some(Instant::now());
+            outgoing.push(Command::Ping(self.clock()));
+        }
+        
+        for command in outgoing {
+            if let Err(e) = self.send(stream, command)
+            && let ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted = e.kind() {
+                return Err(ProtocolError::Disconnection);
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Milliseconds since this protocol was created
+    fn clock(&self) -> u64 {
+        self.created.elapsed().as_millis() as u64
+    }
+    
+    fn measure_rtt(&mut self, ping_time: u64) {
+        self.stats.observe_rtt(Duration::from_millis(self.clock().saturating_sub(ping_time)));
+    }
+    
+    /// Pops the first buffered frame (without its length prefix) if it was entirely received,
+    /// or skips to the next plausible frame boundary if the buffered bytes cannot start a frame
+    fn next_frame(&mut self) -> Result<Option<Vec<u8>>, ProtocolError> {
+        match self.frame_starts_at(0) {
+            None => return Ok(None),
+            Some(false) => {
+                // Bytes too few to tell may be the beginning of the next frame, they are kept
+                let boundary = (1..self.incoming.len())
+                    .find(|offset| self.frame_starts_at(*offset) != Some(false))
+                    .unwrap_or(self.incoming.len());
+                self.incoming.drain(..boundary);
+                self.stats.malformed += 1;
+                return Err(ProtocolError::IllFormatedSequenceNumber);
             },
-            (_, _, _) => Err(ProtocolError::WrongSequence)
-        } 
+            Some(true) => {}
+        }
+        
+        let length = Reader::new(&self.incoming[..Self::LENGTH_SIZE]).read::<u32>().unwrap_or_default() as usize;
+        if length > self.max_frame_length {
+            self.incoming.clear();
+            self.stats.malformed += 1;
+            return Err(ProtocolError::FrameTooLarge);
+        }
+        if self.incoming.len() < Self::LENGTH_SIZE + length {
+            return Ok(None);
+        }
+        
+        let frame = self.incoming[Self::LENGTH_SIZE..Self::LENGTH_SIZE + length].to_vec();
+        self.incoming.drain(..Self::LENGTH_SIZE + length);
+        Ok(Some(frame))
     }
     
-    pub fn send(&mut self, stream: &mut TcpStream, command: Command) -> Result<(), std::io::Error> {
-        let message = [1]
-                .into_iter()
-                .chain(self.last_send)
-                .chain(command.as_bytes())
-                .chain([0])
-                .collect::<Vec<u8>>();
+    /// Whether the length and sequence found at `offset` of the buffered bytes could be those of the next frame,
+    /// `None` if too few bytes are buffered to tell
+    fn frame_starts_at(&self, offset: usize) -> Option<bool> {
+        let mut header = Reader::new(self.incoming.get(offset..offset + Self::LENGTH_SIZE + 4)?);
+        let length = header.read::<u32>().ok()? as usize;
+        let sequence = header.read::<Sequence>().ok()?;
         
-        Self::increment_4_bytes(&mut self.last_send);
+        let close = sequence.distance_from(self.last_reception) <= Self::MAX_SEQUENCE_GAP
+            || self.last_reception.distance_from(sequence) <= Self::MAX_SEQUENCE_GAP;
+        Some((Self::HEADER_SIZE..=Self::MAX_FRAME_LENGTH).contains(&length) && close)
+    }
+    
+    fn open(&mut self, frame: &[u8]) -> Result<Command, ProtocolError> {
+        if frame.len() < Self::HEADER_SIZE {
+            return Err(ProtocolError::IllFormatedSequenceNumber);
+        }
+        
+        let mut header = Reader::new(&frame[..Self::HEADER_SIZE]);
+        let (sequence, acknowledgement) = match (header.read::<Sequence>(), header.read::<Sequence>()) {
+            (Ok(sequence), Ok(acknowledgement)) => (sequence, acknowledgement),
+            _ => return Err(ProtocolError::IllFormatedSequenceNumber)
+        };
+        
+        if !sequence.is_newer_than(self.last_reception) {
+            self.stats.duplicated += 1;
+            return Err(ProtocolError::OutdatedPackage);
+        }
         
-        stream.write_all(&message)
+        self.stats.lost += (sequence.distance_from(self.last_reception) - 1) as u64;
+        self.stats.received += 1;
+        self.last_reception = sequence;
+        self.last_heard = Instant::now();
+        
+        // Acknowledgements of frames we never sent are ignored
+        if acknowledgement.is_newer_than(self.acknowledged) && !acknowledgement.is_newer_than(self.last_send) {
+            self.acknowledged = acknowledgement;
+        }
+        
+        let command = Command::from(&frame[Self::HEADER_SIZE..]);
+        if let Command::Unknown | Command::IllFormated(_) = command {
+            self.stats.malformed += 1;
+        }
+        Ok(command)
     }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
     
-    fn lower_4_bytes(a: &[u8; 4], b: &[u8; 4]) -> bool {
-        for i in 0..4 {
-            if a[i] < b[i] {
-                return true;
-            } else if a[i] > b[i] {
-                return false;
+    use crate::game::component::GameComponent;
+    use crate::game::controller::ControllerKind;
+    use crate::game::object::GameObject;
+    
+    fn round_trip(command: Command) {
+        assert_eq!(Command::from(&command.as_bytes()[..]), command);
+    }
+    
+    #[test]
+    fn every_command_round_trips() {
+        let entity = Entity::from(&GameComponent::from(GameObject::Player));
+        round_trip(Command::Spawn(0, entity.clone()));
+        round_trip(Command::Spawn(usize::MAX, Entity { object: GameObject::CheckPoint { priority: 3 }, controller: ControllerKind::BrainDead, ..entity }));
+        round_trip(Command::Update(42, 0, EntityDelta::moved(vec2(-0.1, 1e-30))));
+        round_trip(Command::Update(1, u64::MAX, EntityDelta { velocity: Some(vec2(f32::MAX, f32::MIN_POSITIVE)), ..EntityDelta::default() }));
+        round_trip(Command::Update(1, 1, EntityDelta {
+            object: Some(GameObject::Projectile),
+            controller: Some(ControllerKind::Monster),
+            position: Some(Vec2::ONE),
+            velocity: Some(Vec2::NEG_ONE),
+            size: Some(vec2(10.0, 5.0))
+        }));
+        round_trip(Command::Update(1, 1, EntityDelta::default()));
+        round_trip(Command::Snapshot(Snapshot { tick: 3, baseline: Some(2), time: 50, changes: Vec::new() }));
+        round_trip(Command::SnapshotAck(u32::MAX));
+        round_trip(Command::Despawn(256));
+        round_trip(Command::ChangeMap { seed: 0x0100_0001, width: 50, height: 1 });
+        round_trip(Command::Unknown);
+        round_trip(Command::IllFormated(FormatError::EmptyMessage));
+        round_trip(Command::IllFormated(FormatError::ByteAfterEnd));
+        round_trip(Command::IllFormated(FormatError::Truncated { expected: 8, available: 3 }));
+        round_trip(Command::IllFormated(FormatError::InvalidText));
+        round_trip(Command::Hello { version: Protocol::VERSION, name: String::from("Zoé"), build: String::new(), session: None });
+        round_trip(Command::Hello { version: Protocol::VERSION, name: String::new(), build: String::new(), session: Some(u64::MAX) });
+        round_trip(Command::Welcome(7, 0xdead_beef));
+        round_trip(Command::Disconnect(DisconnectionReason::Malformed));
+        round_trip(Command::Disconnect(DisconnectionReason::Shutdown));
+        round_trip(Command::Disconnect(DisconnectionReason::Kicked));
+        round_trip(Command::Disconnect(DisconnectionReason::Banned));
+        round_trip(Command::Disconnect(DisconnectionReason::Flooding));
+        round_trip(Command::SystemMessage(String::from("Server restarting in 5 minutes")));
+        round_trip(Command::Chat { sender: String::from("Zoé"), text: String::from("gg ✌") });
+        round_trip(Command::Chat { sender: String::new(), text: String::new() });
+        round_trip(Command::Rejected(RejectionReason::VersionMismatch { server: 3 }));
+        round_trip(Command::Rejected(RejectionReason::ServerFull));
+        round_trip(Command::Rejected(RejectionReason::NameTaken));
+        round_trip(Command::Rejected(RejectionReason::Banned));
+        round_trip(Command::Rejected(RejectionReason::TooManyConnections));
+        round_trip(Command::Input(u32::MAX, PlayerInput { slide: vec2(0.6, -0.8), look: Vec2::ZERO, action: true }));
+        round_trip(Command::Acknowledge { sequence: 12, position: vec2(-3.5, 7.25), velocity: vec2(0.0, -1.0) });
+        round_trip(Command::Ping(u64::MAX));
+        round_trip(Command::Pong(1));
+        round_trip(Command::UdpOffer(7777));
+    }
+    
+    #[test]
+    fn decode_errors_are_typed() {
+        assert_eq!(Command::decode(&[]), Err(FormatError::EmptyMessage));
+        assert_eq!(Command::decode(&[2, 1, 0, 0]), Err(FormatError::Truncated { expected: 8, available: 3 }));
+        
+        let mut too_long = Command::Despawn(3).as_bytes();
+        too_long.push(0);
+        assert_eq!(Command::decode(&too_long), Err(FormatError::ByteAfterEnd));
+        assert_eq!(Command::decode(&[200]), Ok(Command::Unknown));
+        assert_eq!(Command::decode(&[4, 1, 0, 0, 0, 0, 0, 0, 0, 0]), Err(FormatError::ByteAfterEnd));
+        // Fields beyond those of an entity
+        assert_eq!(Command::decode(&[3, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x20]), Err(FormatError::InvalidValue));
+        assert_eq!(Command::decode(&[8, 1, 0, 2, 0, 0xff, 0xfe, 0, 0]), Err(FormatError::InvalidText));
+    }
+    
+    #[test]
+    fn frames_survive_control_bytes_and_fragmentation() {
+        let commands = vec![
+            Command::Spawn(0, Entity::from(&GameComponent::from(GameObject::Monster))),
+            Command::Update(1, 256, EntityDelta::moved(vec2(0.0, f32::from_bits(1)))),
+            Command::ChangeMap { seed: 0x0001_0000, width: 0x0100, height: 1 },
+            Command::Despawn(1)
+        ];
+        
+        let mut wire = Vec::new();
+        let mut sender = Protocol::new();
+        for command in commands.iter().cloned() {
+            sender.send(&mut wire, command).unwrap();
+        }
+        
+        // Delivering the stream one byte at a time must not change anything
+        let mut receiver = Protocol::new();
+        let mut received = Vec::new();
+        for byte in wire.chunks(1) {
+            let mut chunk = byte;
+            while let Ok(command) = receiver.reception(&mut chunk) {
+                received.push(command);
             }
         }
-        return true;
+        
+        assert_eq!(received, commands);
     }
     
-    fn increment_4_bytes(target: &mut [u8;4]) {
-        for i in (0..4).rev() {
-            if !Self::increment_byte(&mut target[i]) {
-                break;
+    /// A socket whose send buffer only has room for `room` more bytes
+    struct Congested {
+        written: Vec<u8>,
+        room: usize
+    }
+    
+    impl Write for Congested {
+        fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+            if self.room == 0 {
+                return Err(ErrorKind::WouldBlock.into());
             }
+            
+            let count = buffer.len().min(self.room);
+            self.written.extend_from_slice(&buffer[..count]);
+            self.room -= count;
+            Ok(count)
+        }
+        
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
         }
     }
     
-    fn increment_byte(target: &mut u8) -> bool {
-        *target = (*target + 1) % u8::MAX;
+    #[test]
+    fn partial_writes_are_resumed() {
+        let mut sender = Protocol::new();
+        let mut socket = Congested { written: Vec::new(), room: 5 };
+        
+        sender.send(&mut socket, Command::Despawn(1)).unwrap();
+        sender.send(&mut socket, Command::Despawn(2)).unwrap();
+        assert!(sender.backlogged());
         
-        *target == 0
+        socket.room = usize::MAX;
+        sender.flush(&mut socket).unwrap();
+        assert!(!sender.backlogged());
+        
+        let mut receiver = Protocol::new();
+        let mut stream = &socket.written[..];
+        assert_eq!(receiver.reception(&mut stream).unwrap(), Command::Despawn(1));
+        assert_eq!(receiver.reception(&mut stream).unwrap(), Command::Despawn(2));
+    }
+    
+    #[test]
+    fn sequences_wrap_and_are_acknowledged() {
+        let mut client = Protocol::new();
+        let mut server = Protocol::new();
+        // As if billions of frames had already been exchanged
+        client.last_send = Sequence(u32::MAX - 1);
+        client.acknowledged = Sequence(u32::MAX - 1);
+        server.last_reception = Sequence(u32::MAX - 1);
+        
+        let mut wire = Vec::new();
+        for id in 0..4 {
+            client.send(&mut wire, Command::Despawn(id)).unwrap();
+        }
+        
+        let mut stream = &wire[..];
+        for id in 0..4 {
+            assert_eq!(server.reception(&mut stream).unwrap(), Command::Despawn(id));
+        }
+        assert_eq!(client.unacknowledged(), 4);
+        
+        let mut answer = Vec::new();
+        server.send(&mut answer, Command::Despawn(0)).unwrap();
+        client.reception(&mut &answer[..]).unwrap();
+        
+        assert_eq!(client.unacknowledged(), 0);
+        assert_eq!(server.stats().lost, 0);
+    }
+    
+    #[test]
+    fn gaps_and_replays_are_counted() {
+        let mut sender = Protocol::new();
+        let mut frames = Vec::new();
+        for id in 0..4 {
+            let mut frame = Vec::new();
+            sender.send(&mut frame, Command::Despawn(id)).unwrap();
+            frames.push(frame);
+        }
+        
+        let mut receiver = Protocol::new();
+        assert!(receiver.reception(&mut &frames[0][..]).is_ok());
+        assert!(receiver.reception(&mut &frames[2][..]).is_ok());
+        assert!(matches!(receiver.reception(&mut &frames[2][..]), Err(ProtocolError::OutdatedPackage)));
+        assert!(matches!(receiver.reception(&mut &frames[1][..]), Err(ProtocolError::OutdatedPackage)));
+        assert!(receiver.reception(&mut &frames[3][..]).is_ok());
+        
+        let stats = receiver.stats();
+        assert_eq!((stats.received, stats.lost, stats.duplicated), (3, 1, 2));
+    }
+    
+    #[test]
+    fn pings_are_answered_and_measured() {
+        let mut client = Protocol::new();
+        let mut server = Protocol::new();
+        
+        let mut wire = Vec::new();
+        client.heartbeat(&mut wire).unwrap();
+        // The ping itself is not handed to the caller
+        assert!(matches!(server.reception(&mut &wire[..]), Err(ProtocolError::Disconnection)));
+        
+        let mut answer = Vec::new();
+        server.heartbeat(&mut answer).unwrap();
+        assert_eq!(client.stats().rtt, None);
+        let _ = client.reception(&mut &answer[..]);
+        
+        assert!(client.stats().rtt.is_some());
+        
+        // The client answers the server's ping, its own next ping is not due yet
+        let mut pong = Vec::new();
+        client.heartbeat(&mut pong).unwrap();
+        let _ = server.reception(&mut &pong[..]);
+        assert!(server.stats().rtt.is_some());
+        assert_eq!(server.stats().received, 2);
+        
+        let exchanged = (wire.len() + pong.len()) as u64;
+        assert_eq!((client.stats().bytes_sent, server.stats().bytes_received), (exchanged, exchanged));
+        assert_eq!(server.stats().bytes_sent, answer.len() as u64);
+    }
+    
+    #[test]
+    fn corrupted_bytes_are_skipped_up_to_the_next_frame() {
+        let mut sender = Protocol::new();
+        let mut wire = Vec::new();
+        sender.send(&mut wire, Command::Despawn(1)).unwrap();
+        wire.extend_from_slice(&[0xff, 0, 0, 0, 0x13, 0x37, 0, 0, 1, 2]);
+        sender.send(&mut wire, Command::Despawn(2)).unwrap();
+        
+        let mut receiver = Protocol::new();
+        let mut stream = &wire[..];
+        assert_eq!(receiver.reception(&mut stream), Ok(Command::Despawn(1)));
+        assert_eq!(receiver.reception(&mut stream), Err(ProtocolError::IllFormatedSequenceNumber));
+        assert_eq!(receiver.reception(&mut stream), Ok(Command::Despawn(2)));
+        assert_eq!(receiver.stats().malformed, 1);
+    }
+    
+    #[test]
+    fn frames_over_the_limit_are_refused() {
+        let mut sender = Protocol::new();
+        let mut wire = Vec::new();
+        sender.send(&mut wire, Command::Chat { sender: String::new(), text: String::from("hi") }).unwrap();
+        sender.send(&mut wire, Command::Chat { sender: String::new(), text: "a".repeat(100) }).unwrap();
+        
+        let mut receiver = Protocol::new().with_max_frame_length(64);
+        let mut stream = &wire[..];
+        assert!(matches!(receiver.reception(&mut stream), Ok(Command::Chat { .. })));
+        assert_eq!(receiver.reception(&mut stream), Err(ProtocolError::FrameTooLarge));
     }
-}
\ No newline at end of file
+    
+    /// Deterministic xorshift, so that failures can be replayed
+    struct Noise(u64);
+    
+    impl Noise {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+        
+        fn bytes(&mut self, count: usize) -> Vec<u8> {
+            (0..count).map(|_| self.next() as u8).collect()
+        }
+    }
+    
+    #[test]
+    fn random_bytes_never_panic() {
+        let mut noise = Noise(0x2545_f491_4f6c_dd1d);
+        
+        for _ in 0..20_000 {
+            let length = noise.next() as usize % 48;
+            let mut bytes = noise.bytes(length);
+            // Known tags reach deeper into the decoders
+            if let Some(tag) = bytes.first_mut() {
+                *tag %= 21;
+            }
+            let _ = Command::from(&bytes[..]);
+        }
+        
+        for _ in 0..500 {
+            // Valid frames with some bytes flipped, delivered in random chunks
+            let mut sender = Protocol::new();
+            let garbage = noise.next() as usize % 16;
+            let mut wire = noise.bytes(garbage);
+            for id in 0..8 {
+                sender.send(&mut wire, Command::Update(id, id as u64, EntityDelta::moved(vec2(id as f32, 0.0)))).unwrap();
+            }
+            for _ in 0..noise.next() % 8 {
+                let index = noise.next() as usize % wire.len();
+                wire[index] = noise.next() as u8;
+            }
+            
+            let mut receiver = Protocol::new();
+            for chunk in wire.chunks(1 + noise.next() as usize % 64) {
+                let mut stream = chunk;
+                while !matches!(receiver.reception(&mut stream), Err(ProtocolError::Pending | ProtocolError::Disconnection)) {}
+            }
+            
+            let mut datagrams = datagram::DatagramProtocol::new();
+            for _ in 0..8 {
+                let length = noise.next() as usize % 64;
+                let _ = datagrams.feed(&noise.bytes(length));
+            }
+        }
+    }
+    
+    #[test]
+    fn silent_peers_time_out() {
+        let mut protocol = Protocol::new().with_idle_timeout(Duration::ZERO);
+        std::thread::sleep(Duration::from_millis(1));
+        
+        assert!(matches!(protocol.heartbeat(&mut Vec::new()), Err(ProtocolError::Disconnection)));
+    }
+}
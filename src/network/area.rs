@@ -0,0 +1,46 @@
+use macroquad::prelude::*;
+
+use crate::game::map::Map;
+
+/// The rooms a client is told about: the one its player is in and those around it.
+/// Entities elsewhere are left out of its snapshots, which spawn and despawn them as they come and go.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Area {
+    line: i32,
+    column: i32
+}
+
+impl Area {
+    /// Rooms seen in every direction from the central one
+    pub const RADIUS: i32 = 1;
+
+    pub fn around(map: &Map, position: Vec2) -> Self {
+        let (line, column) = map.cell_of(position);
+        Self { line, column }
+    }
+
+    pub fn contains(&self, map: &Map, position: Vec2) -> bool {
+        let (line, column) = map.cell_of(position);
+        (line - self.line).abs() <= Self::RADIUS && (column - self.column).abs() <= Self::RADIUS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::game::map::Room;
+
+    #[test]
+    fn neighbouring_rooms_are_in_the_area() {
+        let map = Map::generate(5, 5, 1);
+        let area = Area::around(&map, Map::spawn_point());
+
+        let room = vec2(Room::WIDTH, Room::HEIGHT);
+        assert!(area.contains(&map, Map::spawn_point()));
+        assert!(area.contains(&map, Map::spawn_point() + room));
+        assert!(area.contains(&map, Map::spawn_point() - vec2(room.x, 0.0)));
+        assert!(!area.contains(&map, Map::spawn_point() + vec2(2.0 * room.x, 0.0)));
+        assert!(!area.contains(&map, Map::spawn_point() - vec2(0.0, 2.0 * room.y)));
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::HashSet;
+use std::fs::{ self, OpenOptions };
+use std::io::{ self, ErrorKind, Write };
+use std::path::PathBuf;
+
+/// Player names the server refuses, one per line of `file` so that they outlive the server
+#[derive(Debug, Default)]
+pub struct Bans {
+    names: HashSet<String>,
+    /// Bans only last as long as the server without one
+    file: Option<PathBuf>
+}
+
+impl Bans {
+    /// Reads the names banned so far, a missing file meaning nobody is
+    pub fn load(file: impl Into<PathBuf>) -> io::Result<Self> {
+        let file = file.into();
+        let names = match fs::read_to_string(&file) {
+            Ok(content) => content.lines().map(str::trim).filter(|name| !name.is_empty()).map(String::from).collect(),
+            Err(e) if e.kind() == ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e)
+        };
+
+        Ok(Self { names, file: Some(file) })
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.names.contains(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Bans `name` and appends it to the file. It stays banned until the server stops if the file cannot be written.
+    pub fn add(&mut self, name: &str) -> io::Result<()> {
+        if !self.names.insert(name.to_string()) {
+            return Ok(());
+        }
+
+        let Some(file) = &self.file else { return Ok(()) };
+        let mut file = OpenOptions::new().create(true).append(true).open(file)?;
+        writeln!(file, "{name}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bans_survive_a_restart() {
+        let path = std::env::temp_dir().join(format!("bored-bans-{}.txt", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut bans = Bans::load(&path).unwrap();
+        assert!(bans.is_empty());
+        bans.add("Mallory").unwrap();
+        bans.add("Mallory").unwrap();
+        bans.add("Trudy").unwrap();
+
+        let bans = Bans::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(bans.len(), 2);
+        assert!(bans.contains("Mallory") && bans.contains("Trudy"));
+        assert!(!bans.contains("Alice"));
+    }
+}
@@ -0,0 +1,121 @@
+use macroquad::prelude::*;
+
+use std::collections::VecDeque;
+use std::time::{ Duration, Instant };
+
+use desi_ui::{ Layout, Widget, WidgetData };
+
+/// Something said in the chat, by a player or by the server if `sender` is `None`
+struct Line {
+    sender: Option<String>,
+    text: String,
+    received: Instant
+}
+
+/// The chat of a `GameClient`: what was said lately, fading out after a while,
+/// and a prompt opened with `ChatOverlay::KEY`, submitted with Enter and closed with Escape
+#[derive(Default)]
+pub struct ChatOverlay {
+    prompt: Option<Widget>,
+    lines: VecDeque<Line>
+}
+
+impl ChatOverlay {
+    pub const KEY: KeyCode = KeyCode::T;
+    const PLACEHOLDER: &str = "Say something, commands start with /";
+    const LAYOUT: Layout = Layout { center: vec2(-0.2, 0.45), scale: vec2(0.55, 0.04) };
+    /// Lines kept, and shown while the prompt is open
+    const HISTORY: usize = 10;
+    /// How long lines stay on screen once the prompt is closed, the last second fading them out
+    const SHOWN_FOR: Duration = Duration::from_secs(10);
+    const FADE: Duration = Duration::from_secs(1);
+    const LINE_HEIGHT: f32 = 20.0;
+
+    pub fn is_open(&self) -> bool {
+        self.prompt.is_some()
+    }
+
+    pub fn push(&mut self, sender: Option<String>, text: String) {
+        if self.lines.len() == Self::HISTORY {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(Line { sender, text, received: Instant::now() });
+    }
+
+    /// Opens and closes the prompt, returning what was typed once Enter is pressed
+    pub fn handle_events(&mut self) -> Option<String> {
+        let Some(prompt) = &mut self.prompt else {
+            if is_key_pressed(Self::KEY) {
+                // The key that opened the prompt is not typed in it
+                while get_char_pressed().is_some() {}
+                self.prompt = Some(Self::prompt());
+            }
+            return None;
+        };
+
+        if is_key_pressed(KeyCode::Escape) {
+            self.prompt = None;
+            return None;
+        }
+
+        prompt.update_absolutes(Layout::new(
+            vec2(screen_width() / 2.0, screen_height() / 2.0),
+            vec2(screen_width(), screen_height())
+        ));
+
+        // Clicking elsewhere also activates the prompt, only Enter submits it
+        let typed = prompt.get_activations().into_iter().find_map(|activation| activation.message);
+        if !is_key_pressed(KeyCode::Enter) {
+            return None;
+        }
+
+        self.prompt = None;
+        typed.filter(|text| text != Self::PLACEHOLDER && !text.trim().is_empty())
+    }
+
+    pub fn draw(&self) {
+        let open = self.is_open();
+        let bottom = screen_height() * (0.5 + Self::LAYOUT.center.y - Self::LAYOUT.scale.y / 2.0) - 8.0;
+        let left = screen_width() * (0.5 + Self::LAYOUT.center.x - Self::LAYOUT.scale.x / 2.0);
+
+        for (index, line) in self.lines.iter().rev().enumerate() {
+            let age = line.received.elapsed();
+            let alpha = if open {
+                1.0
+            } else if age >= Self::SHOWN_FOR {
+                continue;
+            } else {
+                ((Self::SHOWN_FOR - age).as_secs_f32() / Self::FADE.as_secs_f32()).min(1.0)
+            };
+
+            let y = bottom - Self::LINE_HEIGHT * index as f32;
+            let text = match &line.sender {
+                Some(sender) => {
+                    let name = format!("{sender}: ");
+                    draw_text(&name, left, y, Self::LINE_HEIGHT, SKYBLUE.with_alpha(alpha));
+                    let offset = measure_text(&name, None, Self::LINE_HEIGHT as u16, 1.0).width;
+                    draw_text(&line.text, left + offset, y, Self::LINE_HEIGHT, WHITE.with_alpha(alpha));
+                    continue;
+                },
+                None => format!("[Server] {}", line.text)
+            };
+            draw_text(text, left, y, Self::LINE_HEIGHT, YELLOW.with_alpha(alpha));
+        }
+
+        if let Some(prompt) = &self.prompt {
+            prompt.draw();
+        }
+    }
+
+    fn prompt() -> Widget {
+        Widget::new(WidgetData::TextInput {
+            placeholder: Self::PLACEHOLDER.to_string(),
+            input: String::new(),
+            selected: true
+        })
+        .with_id("chat")
+        .with_primary(Color::from_rgba(0, 0, 0, 160))
+        .with_secondary(WHITE)
+        .with_relative(Self::LAYOUT)
+    }
+}
@@ -1,36 +1,97 @@
 use std::borrow::BorrowMut;
-use std::net::{ SocketAddr, TcpStream, ToSocketAddrs};
-use std::collections::HashMap;
+use std::fmt;
+use std::net::{ SocketAddr, TcpStream, ToSocketAddrs, UdpSocket };
 use std::sync::{ Mutex, Arc };
-use std::time::Duration;
+use std::time::{ Duration, Instant };
 use std::io::ErrorKind;
 use std::thread::JoinHandle;
 
 use macroquad::prelude::*;
 
+use auto_with::with;
+
 use crate::game::object::GameObject;
+use crate::game::controller::Controller;
+use crate::game::keys::{ KeyBinding, PlayerInput };
 use crate::game::{
     component::*,
     map::Map
 };
 use crate::utils::{ Controlable, Drawable, Dynamic };
 
+use super::interpolation::{ Interpolation, ServerClock };
+use super::prediction::Prediction;
+use super::chat::ChatOverlay;
+use super::replication::EntityStore;
+use super::snapshot::ReceivedSnapshots;
 use super::server::GameServer;
-use super::{ Protocol, Command, GameAgent };
+use super::connection::Connection;
+use super::datagram::DatagramProtocol;
+use super::outbox::{ Outbox, OutboxStats, Overflow };
+use super::transport::{ MemoryConnector, Transport };
+use super::{ Protocol, ProtocolError, ProtocolStats, Command, DisconnectionReason, GameAgent, RejectionReason };
+
+/// What the network thread reports about the connection
+#[derive(Debug, Default, Clone, Copy)]
+struct Link {
+    stats: ProtocolStats,
+    lost: bool,
+    /// Whether commands go through UDP
+    datagrams: bool,
+    /// Reconnection attempt in progress, if the connection dropped
+    reconnecting: Option<u32>,
+    /// Why the connection was closed on purpose, by either end
+    closed: Option<DisconnectionReason>,
+    queues: Backlog
+}
+
+/// How far behind the game and the network thread are on each other
+#[derive(Debug, Default, Clone, Copy)]
+struct Backlog {
+    outbox: OutboxStats,
+    inbox: usize,
+    /// Deepest the inbox has been
+    inbox_peak: usize,
+    /// Times reception was put off because the inbox was full
+    deferred: u64
+}
+
+/// How the server is reached
+#[derive(Clone)]
+enum Route {
+    Network (SocketAddr),
+    Memory (MemoryConnector)
+}
+
+/// What the network thread needs to resume the session after its connection dropped
+struct Session {
+    route: Route,
+    name: String,
+    token: u64
+}
 
 
 pub struct GameClient {
     network_thread: JoinHandle<()>,
     
+    id: usize,
     player: GameComponent,
-    others: HashMap<usize, Rect>,
+    controls: KeyBinding,
+    input: PlayerInput,
+    prediction: Prediction,
+    others: EntityStore,
+    snapshots: ReceivedSnapshots,
+    clock: ServerClock,
+    interpolation: Interpolation,
     map: Map,
     camera: Camera2D,
+    chat: ChatOverlay,
     
     // Thread safe data
     running: Arc<Mutex<bool>>,
+    link: Arc<Mutex<Link>>,
     inbox: Arc<Mutex<Vec<Command>>>,
-    to_send: Arc<Mutex<Vec<Command>>>
+    to_send: Arc<Mutex<Outbox>>
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -38,12 +99,31 @@ pub enum ClientConnectionError {
     UnableToResolve,
     ServerNotFound,
     ElapsedTimeout,
-    ServerRefused
+    ServerRefused,
+    HandshakeFailed,
+    Rejected (RejectionReason)
+}
+
+impl fmt::Display for ClientConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientConnectionError::UnableToResolve => write!(f, "Unable to resolve server address"),
+            ClientConnectionError::ServerNotFound => write!(f, "Server not found"),
+            ClientConnectionError::ElapsedTimeout => write!(f, "Server did not answer in time"),
+            ClientConnectionError::ServerRefused => write!(f, "Connection refused"),
+            ClientConnectionError::HandshakeFailed => write!(f, "Server did not complete the handshake"),
+            ClientConnectionError::Rejected(reason) => write!(f, "Rejected by server: {reason}")
+        }
+    }
 }
 
 impl Controlable for GameClient {
     fn handle_events(&mut self) -> bool {
-        self.player.slide(&self.map);
+        if let Some(text) = self.chat.handle_events() {
+            self.to_send.lock().unwrap().push(Command::Chat { sender: String::new(), text });
+        }
+        // Typing in the chat does not move the player
+        self.input = if self.chat.is_open() { PlayerInput::default() } else { self.controls.poll() };
         true
     }
 }
@@ -52,16 +132,17 @@ impl Drawable for GameClient {
     fn draw(&self) {
         set_camera(&self.camera);
         
-        for (id, r) in self.others.iter() {
-            draw_rectangle(
-                r.x,
-                r.y,
-                r.w,
-                r.h,
-                BLUE);
-            draw_text(&id.to_string(), r.x + 10.0, r.y + 10.0, 13.0, YELLOW);
+        let render_time = self.clock.now() - self.interpolation.delay.as_secs_f64();
+        let max_extrapolation = self.interpolation.max_extrapolation.as_secs_f64();
+        for (id, replica) in self.others.iter() {
+            // Entities that did not move since they were spawned have no snapshot
+            let position = replica.snapshots.sample(render_time, max_extrapolation).unwrap_or(replica.entity.position);
+            replica.entity.to_component(position).draw();
+            draw_text(id.to_string(), position.x + 10.0, position.y + 10.0, 13.0, YELLOW);
         }
-        self.player.draw();
+        let mut shown = self.player.clone();
+        shown.body.position = self.prediction.displayed_position(&self.player);
+        shown.draw();
         
         for room in self.map.get_rooms_iterator() {
             for wall in room.components
@@ -73,6 +154,8 @@ impl Drawable for GameClient {
         }
         
         set_default_camera();
+        
+        self.draw_hud();
     }
 }
 
@@ -80,118 +163,252 @@ impl GameAgent for GameClient {}
 
 impl Dynamic for GameClient {
     fn update(&mut self) {
-                
-        let last_pos = self.player.body().position;
-        // Collisions
-        self.player.collisions(
-            self.map
-                .get_rooms_iterator()
-                .map(|room| &room.components)
-                .flatten()
-                .filter_map(|x|
-                    if let Some(component) = &x {
-                        Some(component)
-                    } else {
-                        None
-                    }
-                ),
-            false
-        );
-        self.player.update();
-        let current_pos = self.player.body().position;
-        
-        self.camera.target = Vec2::lerp(self.camera.target, current_pos + self.player.body().size() / 2.0, 0.3);
-        
-        if current_pos != last_pos {
+        // Our own inputs are simulated right away, the server will correct us if needed
+        let steps = self.prediction.advance(&mut self.player, &self.map, self.input, get_frame_time());
+        if !steps.is_empty() {
             if let Ok(mut to_send) = self.to_send.borrow_mut().lock() {
-                to_send.push(Command::Reposition(0, self.player.body().position()));
+                for (sequence, input) in steps {
+                    to_send.push(Command::Input(sequence, input));
+                }
             }
         }
         
+        let shown = self.prediction.displayed_position(&self.player);
+        self.camera.target = Vec2::lerp(self.camera.target, shown + self.player.body().size() / 2.0, 0.3);
+        
         self.receive();
     }
 }
 
 impl Drop for GameClient {
     fn drop(&mut self) {
-        let _ = self.to_send.borrow_mut().lock().unwrap().push(Command::Despawn(0));
+        let _ = self.to_send.borrow_mut().lock().unwrap().push(Command::Despawn(self.id));
         (*self.running.borrow_mut().lock().unwrap()) = false;
         let _ = std::mem::replace(&mut self.network_thread, std::thread::spawn(|| {1;})).join();
     }
 }
 
 impl GameClient {
-    pub fn new(connection_string: &str) -> Result<Self, ClientConnectionError> {
+    const BUILD: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+    const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+    const CONNECTION_TIMEOUT: Duration = Duration::from_secs(2);
+    /// Delay before the first reconnection attempt, doubled after each failure
+    const RECONNECT_DELAY: Duration = Duration::from_millis(250);
+    const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(4);
+    /// Commands waiting to be sent, a second of inputs: the server would drop older ones anyway
+    const OUTBOX_CAPACITY: usize = 64;
+    /// Commands received but not processed by the game yet, reception pauses beyond that
+    const INBOX_CAPACITY: usize = 1024;
+    
+    pub fn new(connection_string: &str, name: &str) -> Result<Self, ClientConnectionError> {
+        // Performing DNS lookup on connection_string
+        let address: SocketAddr = match connection_string.to_socket_addrs() {
+            Ok(mut addrs) => match addrs.next() {
+                Some(addr) => addr,
+                None => return Err(ClientConnectionError::ServerNotFound)   
+            },
+            Err(_) => return Err(ClientConnectionError::UnableToResolve)
+        };
+        
+        Self::start(Route::Network(address), name, || std::thread::sleep(Duration::from_millis(1)))
+    }
+    
+    /// Joins a server running in this process. The server must keep updating while
+    /// the handshake is waiting for it, which `wait` is called for.
+    pub fn in_memory(connector: MemoryConnector, name: &str, wait: impl FnMut()) -> Result<Self, ClientConnectionError> {
+        Self::start(Route::Memory(connector), name, wait)
+    }
+    
+    // Replaces the settings read by `Interpolation::from_env`
+    with!{ interpolation: Interpolation }
+    
+    fn start(route: Route, name: &str, mut wait: impl FnMut()) -> Result<Self, ClientConnectionError> {
         
         let inbox = Arc::new(Mutex::new(Vec::new()));
-        let to_send = Arc::new(Mutex::new(Vec::new()));
+        let to_send = Arc::new(Mutex::new(Outbox::new(Self::OUTBOX_CAPACITY).with_overflow(Overflow::DropOldest)));
         let running = Arc::new(Mutex::new(true));
+        let link = Arc::new(Mutex::new(Link::default()));
         
-        let network_thread = {
-            let connection_string = connection_string.to_string();
+        let (network_thread, id) = {
             let inbox = inbox.clone();
             let to_send = to_send.clone();
             let running = running.clone();
+            let link = link.clone();
             
-            // Performing DNS lookup on connection_string
-            let address: SocketAddr = match connection_string.to_socket_addrs() {
-                Ok(mut addrs) => match addrs.next() {
-                    Some(addr) => addr,
-                    None => return Err(ClientConnectionError::ServerNotFound)   
-                },
-                Err(_) => return Err(ClientConnectionError::UnableToResolve)
-            };
+            let mut server = Self::connect(&route)?;
+            let mut protocol = Protocol::new();
+            let (id, token) = Self::handshake(&mut server, &mut protocol, name, None, &mut wait)?;
             
-            // Connecting to server
-            let server = match TcpStream::connect_timeout(
-                &address,
-                Duration::from_secs(2)
-            ) {
-                Ok(server) => server,
-                Err(e) => match e.kind() {
-                    ErrorKind::TimedOut => return Err(ClientConnectionError::ElapsedTimeout),
-                    ErrorKind::ConnectionRefused => return Err(ClientConnectionError::ServerRefused),
-                    _ => panic!("Unhandled client connection error !")
-                }
-            };
+            let session = Session { route, name: name.to_string(), token };
+            let connection = Connection::new(server, protocol);
             
-            std::thread::spawn(move || Self::network_worker(server, inbox, to_send, running))
+            (std::thread::spawn(move || Self::network_worker(session, connection, inbox, to_send, running, link)), id)
         };
         
         Ok(Self {
             network_thread,
-            player: GameComponent::from(
-                GameObject::Player
-            ),
-            others: HashMap::new(),
+            id,
+            player: GameComponent::from(GameObject::Player)
+                .with_controller(Controller::default().into_remote()),
+            controls: KeyBinding::default(),
+            input: PlayerInput::default(),
+            prediction: Prediction::default(),
+            others: EntityStore::default(),
+            snapshots: ReceivedSnapshots::default(),
+            clock: ServerClock::default(),
+            interpolation: Interpolation::from_env(),
             map: Default::default(),
             camera: Camera2D::from_display_rect(Rect { x: 0.0, y: 600.0, w: 800.0, h: -600.0 }),
+            chat: ChatOverlay::default(),
             running,
+            link,
             to_send,
             inbox
         })
     }
     
+    /// Opens a non-blocking transport to the server
+    fn connect(route: &Route) -> Result<Box<dyn Transport>, ClientConnectionError> {
+        match route {
+            Route::Network(address) => {
+                let server = TcpStream::connect_timeout(address, Self::CONNECTION_TIMEOUT).map_err(|e| match e.kind() {
+                    ErrorKind::TimedOut => ClientConnectionError::ElapsedTimeout,
+                    ErrorKind::ConnectionRefused => ClientConnectionError::ServerRefused,
+                    _ => ClientConnectionError::ServerNotFound
+                })?;
+                server.set_nonblocking(true).map_err(|_| ClientConnectionError::ServerRefused)?;
+                Ok(Box::new(server))
+            },
+            Route::Memory(connector) => match connector.connect() {
+                Some(server) => Ok(Box::new(server)),
+                None => Err(ClientConnectionError::ServerNotFound)
+            }
+        }
+    }
+    
+    /// Introduces ourselves to the server, resuming `session` if given,
+    /// and returns the id it assigned us with our session token
+    fn handshake(
+        server: &mut Box<dyn Transport>,
+        protocol: &mut Protocol,
+        name: &str,
+        session: Option<u64>,
+        wait: &mut impl FnMut()
+    ) -> Result<(usize, u64), ClientConnectionError> {
+        let deadline = Instant::now() + Self::HANDSHAKE_TIMEOUT;
+        
+        let hello = Command::Hello {
+            version: Protocol::VERSION,
+            name: name.to_string(),
+            build: Self::BUILD.to_string(),
+            session
+        };
+        if protocol.send(server, hello).is_err() {
+            return Err(ClientConnectionError::HandshakeFailed);
+        }
+        
+        while Instant::now() < deadline {
+            match protocol.reception(server) {
+                Ok(Command::Welcome(id, token)) => return Ok((id, token)),
+                Ok(Command::Rejected(reason)) => return Err(ClientConnectionError::Rejected(reason)),
+                Err(ProtocolError::Disconnection) => return Err(ClientConnectionError::HandshakeFailed),
+                Err(ProtocolError::Pending) => wait(),
+                _ => {}
+            }
+        }
+        
+        Err(ClientConnectionError::ElapsedTimeout)
+    }
+    
     fn network_worker(
-        mut server: TcpStream,
+        mut session: Session,
+        mut connection: Connection,
         mut inbox: Arc<Mutex<Vec<Command>>>,
-        mut to_send: Arc<Mutex<Vec<Command>>>,
-        mut running: Arc<Mutex<bool>>
+        mut to_send: Arc<Mutex<Outbox>>,
+        mut running: Arc<Mutex<bool>>,
+        link: Arc<Mutex<Link>>
     ) {
-        
-        server.set_nonblocking(true).unwrap();
-        
-        let mut protocol = Protocol::new();
+        let mut queues = Backlog::default();
         
         loop {
-            // Reception
-            if let Ok(command) = protocol.reception(&mut server) {
-                inbox.borrow_mut().lock().unwrap().push(command);
+            // Reception of everything that arrived since last time, or of what fits in the inbox if the game is late.
+            // What is left waits in the socket, and the server coalesces what it has to send in the meantime.
+            let mut lost = false;
+            let mut closed = None;
+            let mut room = Self::INBOX_CAPACITY.saturating_sub(inbox.lock().unwrap().len());
+            let mut received = Vec::new();
+            loop {
+                if room == 0 {
+                    queues.deferred += 1;
+                    break;
+                }
+                
+                match connection.reception() {
+                    Ok(Command::UdpOffer(port)) => Self::open_datagrams(&mut connection, &session, port),
+                    Ok(Command::Disconnect(reason)) => {
+                        closed = Some(reason);
+                        break;
+                    },
+                    Ok(command @ (Command::Unknown | Command::IllFormated(_))) => eprintln!(
+                        "DEBUG: Ignored a malformed command from the server: {command:?} ({}/{})",
+                        connection.malformed(),
+                        Connection::MAX_MALFORMED
+                    ),
+                    Ok(command) => {
+                        received.push(command);
+                        room -= 1;
+                    },
+                    Err(ProtocolError::Disconnection) => {
+                        lost = true;
+                        break;
+                    },
+                    Err(ProtocolError::Pending) => break,
+                    Err(ProtocolError::IllFormatedSequenceNumber) => eprintln!(
+                        "DEBUG: Skipped a corrupted frame from the server ({}/{})",
+                        connection.malformed(),
+                        Connection::MAX_MALFORMED
+                    ),
+                    Err(_) => {}
+                }
+            }
+            
+            if closed.is_none() && connection.malformed() >= Connection::MAX_MALFORMED {
+                connection.close(DisconnectionReason::Malformed);
+                closed = Some(DisconnectionReason::Malformed);
+            }
+            
+            {
+                let mut inbox = inbox.borrow_mut().lock().unwrap();
+                inbox.extend(received);
+                queues.inbox = inbox.len();
+                queues.inbox_peak = queues.inbox_peak.max(inbox.len());
+            }
+            
+            // Sending the whole batch, in the order commands were queued
+            let commands = {
+                let mut to_send = to_send.borrow_mut().lock().unwrap();
+                queues.outbox = to_send.stats();
+                to_send.drain()
+            };
+            for command in commands {
+                let _ = connection.send(command);
             }
             
-            // Sending
-            if let Some(to_send) = to_send.borrow_mut().lock().unwrap().pop() {
-                let _ = protocol.send(&mut server, to_send);
+            lost |= connection.heartbeat().is_err();
+            *link.lock().unwrap() = Link {
+                stats: connection.stats(),
+                lost: false,
+                datagrams: connection.uses_datagrams(),
+                reconnecting: None,
+                closed,
+                queues
+            };
+            
+            // The server will not resume a session that was closed on purpose
+            if let Some(reason) = closed {
+                eprintln!("DEBUG: Disconnected: {reason}");
+                link.lock().unwrap().lost = true;
+                break;
             }
             
             // End of thread condition
@@ -199,33 +416,231 @@ impl GameClient {
                 break;
             }
             
+            if lost {
+                match Self::reconnect(&mut session, &inbox, &to_send, &running, &link) {
+                    Some(resumed) => connection = resumed,
+                    None => {
+                        link.lock().unwrap().lost = true;
+                        break;
+                    }
+                }
+            }
+            
             std::thread::sleep(Duration::from_millis(16));
         }
     }
  
+    /// Tries to resume `session` with an exponential backoff, for as long as the server keeps it.
+    /// The server sends the world again on success, a `Command::Welcome` is queued first to tell `receive`.
+    fn reconnect(
+        session: &mut Session,
+        inbox: &Arc<Mutex<Vec<Command>>>,
+        to_send: &Arc<Mutex<Outbox>>,
+        running: &Arc<Mutex<bool>>,
+        link: &Arc<Mutex<Link>>
+    ) -> Option<Connection> {
+        let started = Instant::now();
+        let mut delay = Self::RECONNECT_DELAY;
+        let mut attempt = 0;
+        
+        while started.elapsed() < GameServer::SESSION_GRACE {
+            attempt += 1;
+            link.lock().unwrap().reconnecting = Some(attempt);
+            // Inputs produced while offline would only be dropped by the server
+            to_send.lock().unwrap().drain();
+            
+            let mut protocol = Protocol::new();
+            let resumed = Self::connect(&session.route).and_then(|mut server| {
+                // The server runs on its own, from another thread or another machine
+                let mut wait = || std::thread::sleep(Duration::from_millis(1));
+                Self::handshake(&mut server, &mut protocol, &session.name, Some(session.token), &mut wait)
+                    .map(|welcome| (server, welcome))
+            });
+            
+            match resumed {
+                Ok((server, (id, token))) => {
+                    session.token = token;
+                    inbox.lock().unwrap().push(Command::Welcome(id, token));
+                    return Some(Connection::new(server, protocol));
+                },
+                Err(ClientConnectionError::Rejected(_)) => return None,
+                Err(_) => {}
+            }
+            
+            let retry = Instant::now() + delay;
+            while Instant::now() < retry {
+                if !*running.lock().unwrap() {
+                    return None;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            delay = (delay * 2).min(Self::MAX_RECONNECT_DELAY);
+        }
+        
+        None
+    }
+    
+    /// Answers a `Command::UdpOffer`, the stream is kept if the server cannot be reached over UDP
+    fn open_datagrams(connection: &mut Connection, session: &Session, port: u16) {
+        let Route::Network(address) = &session.route else { return };
+        let local = if address.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let Ok(socket) = UdpSocket::bind(local) else { return };
+        if socket.connect((address.ip(), port)).is_err() || socket.set_nonblocking(true).is_err() {
+            return;
+        }
+        
+        connection.attach(socket, DatagramProtocol::new(), false);
+        let _ = connection.send_datagram(Command::Hello {
+            version: Protocol::VERSION,
+            name: session.name.clone(),
+            build: Self::BUILD.to_string(),
+            session: Some(session.token)
+        });
+    }
+    
+    /// Connection quality, in screen space
+    fn draw_hud(&self) {
+        let link = *self.link.lock().unwrap();
+        
+        let text = if let Some(reason) = link.closed {
+            format!("Disconnected: {reason}")
+        } else if link.lost {
+            String::from("Connection lost")
+        } else if let Some(attempt) = link.reconnecting {
+            format!("Connection dropped, reconnecting (attempt {attempt})...")
+        } else if let Some(rtt) = link.stats.rtt {
+            format!(
+                "RTT {} ms (jitter {} ms, {})",
+                rtt.as_millis(),
+                link.stats.jitter.as_millis(),
+                if link.datagrams { "UDP" } else { "TCP" }
+            )
+        } else {
+            String::from("RTT ...")
+        };
+        
+        draw_text(&text, 10.0, 20.0, 20.0, if link.lost || link.reconnecting.is_some() { RED } else { YELLOW });
+        
+        let queues = link.queues;
+        let backlog = format!(
+            "Queued: {} out (peak {}, coalesced {}, dropped {}), {} in (peak {}, deferred {})",
+            queues.outbox.depth,
+            queues.outbox.peak,
+            queues.outbox.coalesced,
+            queues.outbox.dropped,
+            queues.inbox,
+            queues.inbox_peak,
+            queues.deferred
+        );
+        draw_text(&backlog, 10.0, 40.0, 16.0, if queues.outbox.dropped > 0 || queues.deferred > 0 { ORANGE } else { GRAY });
+        
+        self.chat.draw();
+    }
+    
     fn receive(&mut self) {
-        if let Some(command) = self.inbox.borrow_mut().lock().unwrap().pop() {
+        let commands = std::mem::take(&mut *self.inbox.borrow_mut().lock().unwrap());
+        for command in commands {
             match command {
-                Command::Spawn(id) => {
-                    self.others.insert(id, Rect { x: 0.0, y: 0.0, w: 50.0, h: 50.0 });
+                Command::Spawn(id, entity) => {
+                    if id != self.id {
+                        self.others.spawn(id, entity);
+                    }
+                },
+                Command::Update(id, _, delta) if id == self.id => {
+                    // The server is authoritative over our own position
+                    if let Some(position) = delta.position {
+                        self.player.body.position = position;
+                    }
                 },
-                Command::Reposition(id, pos) => if let Some(other) = self.others.get_mut(&id) {
-                    let before = other.point();
-                    let after = before + (pos - before) / 4.0;
-                    other.x = after.x;
-                    other.y = after.y;
-                } else {
-                    self.others.insert(id, Rect { x: pos.x, y: pos.y, w: 50.0, h: 50.0 });
+                Command::Acknowledge { sequence, position, velocity } => {
+                    self.prediction.reconcile(&mut self.player, &self.map, sequence, position, velocity);
+                },
+                Command::Update(id, time, delta) => {
+                    let time = time as f64 / 1000.0;
+                    self.clock.observe(time);
+                    self.others.update(id, time, &delta);
                 },
                 Command::Despawn(id) => {
-                    self.others.remove(&id);
+                    self.others.despawn(id);
+                },
+                Command::Snapshot(snapshot) => {
+                    let Some(world) = self.snapshots.receive(&snapshot) else { continue };
+                    let time = snapshot.time as f64 / 1000.0;
+                    self.clock.observe(time);
+                    self.others.sync(world, time);
+                    self.to_send.lock().unwrap().push(Command::SnapshotAck(snapshot.tick));
+                },
+                Command::ChangeMap { seed, width, height } => {
+                    self.map = Map::generate(width.clamp(1, Map::MAX_SIDE), height.clamp(1, Map::MAX_SIDE), seed);
+                },
+                Command::SystemMessage(text) => {
+                    self.chat.push(None, text);
+                },
+                Command::Chat { sender, text } => {
+                    self.chat.push(Some(sender), text);
+                },
+                Command::Welcome(id, _) => {
+                    // Queued by the network thread after a reconnection, the world is about to be sent again
+                    self.id = id;
+                    self.others.clear();
+                    self.snapshots.clear();
+                },
+                Command::Hello { .. } | Command::Rejected(_) => {
+                    // Only meaningful during the handshake
                 },
-                Command::ChangeMap(seed) => {
-                    self.map = Map::generate(GameServer::MAP_WIDTH, GameServer::MAP_HEIGHT, seed);
+                Command::Input(..) | Command::SnapshotAck(_) => {
+                    // Only meaningful to the server
                 },
-                Command::Unknown => todo!(),
-                Command::IllFormated(_) => todo!()
+                Command::Ping(_)
+                | Command::Pong(_)
+                | Command::UdpOffer(_)
+                | Command::Disconnect(_)
+                | Command::Unknown
+                | Command::IllFormated(_) => {
+                    // Handled by the network thread
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// Runs the server and lets the clients read what they received, until `done`
+    fn run_until(server: &mut GameServer, clients: &mut [&mut GameClient], done: impl Fn(&[&mut GameClient]) -> bool) {
+        for _ in 0..2000 {
+            server.update();
+            clients.iter_mut().for_each(|client| client.receive());
+            if done(clients) {
+                return;
             }
+            std::thread::sleep(Duration::from_millis(1));
         }
+        panic!("The clients did not reach the expected state");
     }
-}
\ No newline at end of file
+    
+    #[test]
+    fn clients_meet_through_an_in_memory_server() {
+        let mut server = GameServer::offline();
+        let connector = server.connector();
+        
+        let mut alice = GameClient::in_memory(connector.clone(), "Alice", || server.update()).unwrap();
+        let mut bob = GameClient::in_memory(connector.clone(), "Bob", || server.update()).unwrap();
+        assert_ne!(alice.id, bob.id);
+        
+        let rejected = GameClient::in_memory(connector, "Bob", || server.update());
+        assert!(matches!(rejected, Err(ClientConnectionError::Rejected(RejectionReason::NameTaken))));
+        
+        run_until(&mut server, &mut [&mut alice, &mut bob], |clients| {
+            clients[0].others.get(clients[1].id).is_some() && clients[1].others.get(clients[0].id).is_some()
+        });
+        assert_eq!(alice.others.get(bob.id).unwrap().object, GameObject::Player);
+        assert!(alice.map.get_rooms_iterator().count() > 0);
+        
+        let bob_id = bob.id;
+        drop(bob);
+        run_until(&mut server, &mut [&mut alice], |clients| clients[0].others.get(bob_id).is_none());
+    }
+}
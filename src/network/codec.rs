@@ -0,0 +1,375 @@
+use macroquad::prelude::*;
+
+use crate::game::controller::ControllerKind;
+use crate::game::keys::PlayerInput;
+use crate::game::object::GameObject;
+
+use super::discovery::Announcement;
+use super::replication::{ Entity, EntityDelta };
+use super::{ DisconnectionReason, FormatError, RejectionReason };
+
+/// A value that can be written in, and read back from, a command body.
+/// Integers and floats are fixed-width little-endian, `usize` is always sent on 8 bytes.
+pub trait Shareable: Sized {
+    fn encode(&self, bytes: &mut Vec<u8>);
+    fn decode(reader: &mut Reader) -> Result<Self, FormatError>;
+}
+
+/// Cursor over a received command body.
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    cursor: usize
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, cursor: 0 }
+    }
+
+    pub fn read<T: Shareable>(&mut self) -> Result<T, FormatError> {
+        T::decode(self)
+    }
+
+    pub fn take(&mut self, count: usize) -> Result<&'a [u8], FormatError> {
+        let available = self.bytes.len() - self.cursor;
+        if count > available {
+            return Err(FormatError::Truncated { expected: count, available });
+        }
+
+        let taken = &self.bytes[self.cursor..self.cursor + count];
+        self.cursor += count;
+        Ok(taken)
+    }
+
+    /// Everything left of the body
+    pub fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.bytes[self.cursor..];
+        self.cursor = self.bytes.len();
+        rest
+    }
+
+    /// Checks that the whole body has been consumed
+    pub fn finish(&self) -> Result<(), FormatError> {
+        if self.cursor == self.bytes.len() {
+            Ok(())
+        } else {
+            Err(FormatError::ByteAfterEnd)
+        }
+    }
+}
+
+macro_rules! shareable_number {
+    ($($t: ty),*) => {
+        $(
+            impl Shareable for $t {
+                fn encode(&self, bytes: &mut Vec<u8>) {
+                    bytes.extend_from_slice(&self.to_le_bytes());
+                }
+
+                fn decode(reader: &mut Reader) -> Result<Self, FormatError> {
+                    let raw = reader.take(size_of::<$t>())?;
+                    Ok(<$t>::from_le_bytes(raw.try_into().unwrap()))
+                }
+            }
+        )*
+    };
+}
+
+shareable_number!(u8, u16, u32, u64, f32);
+
+impl Shareable for usize {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        (*self as u64).encode(bytes);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, FormatError> {
+        usize::try_from(reader.read::<u64>()?).map_err(|_| FormatError::InvalidValue)
+    }
+}
+
+impl Shareable for bool {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        (*self as u8).encode(bytes);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, FormatError> {
+        match reader.read::<u8>()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(FormatError::InvalidValue)
+        }
+    }
+}
+
+/// Strings are prefixed with their byte length on 2 bytes
+impl Shareable for String {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        let text = &self.as_bytes()[..self.len().min(u16::MAX as usize)];
+        (text.len() as u16).encode(bytes);
+        bytes.extend_from_slice(text);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, FormatError> {
+        let length = reader.read::<u16>()? as usize;
+        String::from_utf8(reader.take(length)?.to_vec()).map_err(|_| FormatError::InvalidText)
+    }
+}
+
+/// Options are prefixed with a presence flag
+impl<T: Shareable> Shareable for Option<T> {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.is_some().encode(bytes);
+        if let Some(value) = self {
+            value.encode(bytes);
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, FormatError> {
+        if reader.read::<bool>()? {
+            Ok(Some(reader.read()?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl Shareable for Vec2 {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.x.encode(bytes);
+        self.y.encode(bytes);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, FormatError> {
+        Ok(vec2(reader.read()?, reader.read()?))
+    }
+}
+
+impl Shareable for PlayerInput {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.slide.encode(bytes);
+        self.look.encode(bytes);
+        self.action.encode(bytes);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, FormatError> {
+        Ok(PlayerInput { slide: reader.read()?, look: reader.read()?, action: reader.read()? })
+    }
+}
+
+impl Shareable for FormatError {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        match self {
+            FormatError::EmptyMessage => 0u8.encode(bytes),
+            FormatError::MissingField => 1u8.encode(bytes),
+            FormatError::WrongType => 2u8.encode(bytes),
+            FormatError::InvalidValue => 3u8.encode(bytes),
+            FormatError::ByteAfterEnd => 4u8.encode(bytes),
+            FormatError::Truncated { expected, available } => {
+                5u8.encode(bytes);
+                expected.encode(bytes);
+                available.encode(bytes);
+            },
+            FormatError::InvalidText => 6u8.encode(bytes)
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, FormatError> {
+        match reader.read::<u8>()? {
+            0 => Ok(FormatError::EmptyMessage),
+            1 => Ok(FormatError::MissingField),
+            2 => Ok(FormatError::WrongType),
+            3 => Ok(FormatError::InvalidValue),
+            4 => Ok(FormatError::ByteAfterEnd),
+            5 => Ok(FormatError::Truncated { expected: reader.read()?, available: reader.read()? }),
+            6 => Ok(FormatError::InvalidText),
+            _ => Err(FormatError::InvalidValue)
+        }
+    }
+}
+
+impl Shareable for RejectionReason {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        match self {
+            RejectionReason::VersionMismatch { server } => {
+                0u8.encode(bytes);
+                server.encode(bytes);
+            },
+            RejectionReason::ServerFull => 1u8.encode(bytes),
+            RejectionReason::NameTaken => 2u8.encode(bytes),
+            RejectionReason::Banned => 3u8.encode(bytes),
+            RejectionReason::TooManyConnections => 4u8.encode(bytes)
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, FormatError> {
+        match reader.read::<u8>()? {
+            0 => Ok(RejectionReason::VersionMismatch { server: reader.read()? }),
+            1 => Ok(RejectionReason::ServerFull),
+            2 => Ok(RejectionReason::NameTaken),
+            3 => Ok(RejectionReason::Banned),
+            4 => Ok(RejectionReason::TooManyConnections),
+            _ => Err(FormatError::InvalidValue)
+        }
+    }
+}
+
+impl Shareable for DisconnectionReason {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        match self {
+            DisconnectionReason::Malformed => 0u8.encode(bytes),
+            DisconnectionReason::Shutdown => 1u8.encode(bytes),
+            DisconnectionReason::Kicked => 2u8.encode(bytes),
+            DisconnectionReason::Banned => 3u8.encode(bytes),
+            DisconnectionReason::Flooding => 4u8.encode(bytes)
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, FormatError> {
+        match reader.read::<u8>()? {
+            0 => Ok(DisconnectionReason::Malformed),
+            1 => Ok(DisconnectionReason::Shutdown),
+            2 => Ok(DisconnectionReason::Kicked),
+            3 => Ok(DisconnectionReason::Banned),
+            4 => Ok(DisconnectionReason::Flooding),
+            _ => Err(FormatError::InvalidValue)
+        }
+    }
+}
+
+impl Shareable for Announcement {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.name.encode(bytes);
+        self.players.encode(bytes);
+        self.max_players.encode(bytes);
+        self.seed.encode(bytes);
+        self.version.encode(bytes);
+        self.port.encode(bytes);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, FormatError> {
+        Ok(Announcement {
+            name: reader.read()?,
+            players: reader.read()?,
+            max_players: reader.read()?,
+            seed: reader.read()?,
+            version: reader.read()?,
+            port: reader.read()?
+        })
+    }
+}
+
+impl Shareable for GameObject {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        match self {
+            GameObject::Player => 0u8.encode(bytes),
+            GameObject::Monster => 1u8.encode(bytes),
+            GameObject::CheckPoint { priority } => {
+                2u8.encode(bytes);
+                priority.encode(bytes);
+            },
+            GameObject::Wall => 3u8.encode(bytes),
+            GameObject::Projectile => 4u8.encode(bytes)
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, FormatError> {
+        match reader.read::<u8>()? {
+            0 => Ok(GameObject::Player),
+            1 => Ok(GameObject::Monster),
+            2 => Ok(GameObject::CheckPoint { priority: reader.read()? }),
+            3 => Ok(GameObject::Wall),
+            4 => Ok(GameObject::Projectile),
+            _ => Err(FormatError::InvalidValue)
+        }
+    }
+}
+
+impl Shareable for ControllerKind {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        match self {
+            ControllerKind::Player => 0u8.encode(bytes),
+            ControllerKind::Monster => 1u8.encode(bytes),
+            ControllerKind::BrainDead => 2u8.encode(bytes)
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, FormatError> {
+        match reader.read::<u8>()? {
+            0 => Ok(ControllerKind::Player),
+            1 => Ok(ControllerKind::Monster),
+            2 => Ok(ControllerKind::BrainDead),
+            _ => Err(FormatError::InvalidValue)
+        }
+    }
+}
+
+impl Shareable for Entity {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.object.encode(bytes);
+        self.controller.encode(bytes);
+        self.position.encode(bytes);
+        self.velocity.encode(bytes);
+        self.size.encode(bytes);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, FormatError> {
+        Ok(Entity {
+            object: reader.read()?,
+            controller: reader.read()?,
+            position: reader.read()?,
+            velocity: reader.read()?,
+            size: reader.read()?
+        })
+    }
+}
+
+/// A byte flags the fields present, in declaration order from the lowest bit, followed by their values
+impl Shareable for EntityDelta {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        let present = [
+            self.object.is_some(),
+            self.controller.is_some(),
+            self.position.is_some(),
+            self.velocity.is_some(),
+            self.size.is_some()
+        ];
+        let mask = present.iter().enumerate().fold(0u8, |mask, (bit, present)| mask | (*present as u8) << bit);
+        mask.encode(bytes);
+
+        if let Some(object) = &self.object {
+            object.encode(bytes);
+        }
+        if let Some(controller) = &self.controller {
+            controller.encode(bytes);
+        }
+        for vector in [self.position, self.velocity, self.size].into_iter().flatten() {
+            vector.encode(bytes);
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, FormatError> {
+        let mask = reader.read::<u8>()?;
+        if mask >> 5 != 0 {
+            return Err(FormatError::InvalidValue);
+        }
+
+        let present = |bit: u8| mask & (1 << bit) != 0;
+        let mut delta = EntityDelta::default();
+        if present(0) {
+            delta.object = Some(reader.read()?);
+        }
+        if present(1) {
+            delta.controller = Some(reader.read()?);
+        }
+        if present(2) {
+            delta.position = Some(reader.read()?);
+        }
+        if present(3) {
+            delta.velocity = Some(reader.read()?);
+        }
+        if present(4) {
+            delta.size = Some(reader.read()?);
+        }
+        Ok(delta)
+    }
+}
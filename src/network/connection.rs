@@ -0,0 +1,143 @@
+use std::io;
+use std::net::UdpSocket;
+
+use super::datagram::DatagramProtocol;
+use super::readiness::{ self, Interest };
+use super::transport::Transport;
+use super::{ Command, DisconnectionReason, Protocol, ProtocolError, ProtocolStats };
+
+/// The UDP side of a `Connection`
+struct Datagrams {
+    socket: UdpSocket,
+    protocol: DatagramProtocol,
+    /// Set once the peer answered over UDP, commands go through the stream until then
+    established: bool
+}
+
+/// A peer reached through the transport of its handshake, then through UDP if both ends manage to.
+///
+/// The server offers a socket with `Command::UdpOffer` and the client sends a `Command::Hello`
+/// carrying its session token to it, which the server answers with a `Command::Welcome`.
+/// From then on, every command goes through the UDP channels and the stream only carries heartbeats.
+pub struct Connection {
+    stream: Box<dyn Transport>,
+    protocol: Protocol,
+    datagrams: Option<Datagrams>
+}
+
+impl Connection {
+    /// Frames and commands a peer may send that cannot be understood before it is disconnected
+    pub const MAX_MALFORMED: u64 = 16;
+    
+    pub fn new(stream: Box<dyn Transport>, protocol: Protocol) -> Self {
+        Self { stream, protocol, datagrams: None }
+    }
+
+    /// Adds the UDP channels, `socket` being connected to the peer.
+    /// They are used right away if `established`, otherwise once a `Command::Welcome` comes through them.
+    pub fn attach(&mut self, socket: UdpSocket, protocol: DatagramProtocol, established: bool) {
+        self.datagrams = Some(Datagrams { socket, protocol, established });
+    }
+
+    pub fn uses_datagrams(&self) -> bool {
+        self.datagrams.as_ref().is_some_and(|datagrams| datagrams.established)
+    }
+
+    pub fn stats(&self) -> ProtocolStats {
+        match &self.datagrams {
+            Some(datagrams) if datagrams.established => datagrams.protocol.stats(),
+            _ => self.protocol.stats()
+        }
+    }
+
+    /// Frames and commands received through either transport that could not be understood
+    pub fn malformed(&self) -> u64 {
+        let datagrams = self.datagrams.as_ref().map_or(0, |datagrams| datagrams.protocol.stats().malformed);
+        self.protocol.stats().malformed + datagrams
+    }
+    
+    /// Bytes sent and received through either transport
+    pub fn transferred(&self) -> (u64, u64) {
+        let datagrams = self.datagrams.as_ref().map_or(ProtocolStats::default(), |datagrams| datagrams.protocol.stats());
+        let stream = self.protocol.stats();
+        (stream.bytes_sent + datagrams.bytes_sent, stream.bytes_received + datagrams.bytes_received)
+    }
+    
+    pub fn unacknowledged(&self) -> u32 {
+        match &self.datagrams {
+            Some(datagrams) if datagrams.established => datagrams.protocol.unacknowledged(),
+            _ => self.protocol.unacknowledged()
+        }
+    }
+
+    /// Snapshots too large for a datagram, such as the full ones, go through the stream
+    pub fn send(&mut self, command: Command) -> io::Result<()> {
+        let oversized = matches!(command, Command::Snapshot(_)) && !DatagramProtocol::fits(&command);
+        match &mut self.datagrams {
+            Some(datagrams) if datagrams.established && !oversized => datagrams.protocol.send(&datagrams.socket, command),
+            _ => self.protocol.send(&mut self.stream, command)
+        }
+    }
+
+    /// Sends `command` over UDP even if the channels are not established yet
+    pub fn send_datagram(&mut self, command: Command) -> io::Result<()> {
+        match &mut self.datagrams {
+            Some(datagrams) => datagrams.protocol.send(&datagrams.socket, command),
+            None => Err(io::ErrorKind::NotConnected.into())
+        }
+    }
+
+    /// Whether the stream could not take everything that was sent yet
+    pub fn backlogged(&self) -> bool {
+        self.protocol.backlogged()
+    }
+
+    /// Tells the peer why the connection is about to be dropped, through the stream which still works if datagrams do not
+    pub fn close(&mut self, reason: DisconnectionReason) {
+        let _ = self.protocol.send(&mut self.stream, Command::Disconnect(reason));
+    }
+    
+    /// Writes what the stream could not take earlier
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.protocol.flush(&mut self.stream)
+    }
+
+    /// Sockets to wait on for this connection to make progress
+    pub fn interests(&self) -> Vec<Interest> {
+        let stream = self.stream.descriptor().map(|descriptor| Interest { descriptor, write: self.backlogged() });
+        let datagrams = self.datagrams
+            .as_ref()
+            .and_then(|datagrams| readiness::descriptor(&datagrams.socket))
+            .map(|descriptor| Interest { descriptor, write: false });
+
+        stream.into_iter().chain(datagrams).collect()
+    }
+
+    /// Next command from either transport, UDP first
+    pub fn reception(&mut self) -> Result<Command, ProtocolError> {
+        if let Some(datagrams) = &mut self.datagrams {
+            match datagrams.protocol.reception(&datagrams.socket) {
+                Ok(Command::Welcome(..)) if !datagrams.established => {
+                    datagrams.established = true;
+                    return self.reception();
+                },
+                Err(ProtocolError::Pending) => {},
+                received => return received
+            }
+        }
+
+        self.protocol.reception(&mut self.stream)
+    }
+
+    /// Heartbeats of both transports, the stream deciding when the peer is gone
+    pub fn heartbeat(&mut self) -> Result<(), ProtocolError> {
+        self.protocol.heartbeat(&mut self.stream)?;
+
+        if let Some(datagrams) = &mut self.datagrams {
+            // A firewall may silently drop datagrams, this is not a disconnection
+            let _ = datagrams.protocol.heartbeat(&datagrams.socket);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use macroquad::prelude::*;
+
+use std::io::BufRead;
+use std::str::FromStr;
+use std::sync::mpsc::{ self, Receiver };
+use std::thread;
+
+/// An administration command typed in the server console, see `Order::HELP`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Order {
+    Help,
+    List,
+    Kick (usize),
+    Ban (String),
+    Say (String),
+    /// Regenerates the map with the same size, from the given seed or a random one
+    NewMap (Option<usize>),
+    Teleport (usize, Vec2),
+    MaxPlayers (usize),
+    Shutdown
+}
+
+impl Order {
+    pub const HELP: &str = "\
+help                 Lists the commands
+list                 Lists the players
+kick <id>            Disconnects a player, who may join again
+ban <name>           Disconnects a player and refuses its name from now on
+say <message>        Shows a message to every player
+newmap [seed]        Generates a new map and sends everyone back to the spawn point
+tp <id> <x> <y>      Moves a player
+maxplayers <count>   Changes how many players may join
+shutdown             Disconnects everyone and stops the server";
+
+    fn argument<T: FromStr>(name: &str, value: Option<&str>) -> Result<T, String> {
+        let value = value.ok_or(format!("missing {name}"))?;
+        value.parse().map_err(|_| format!("invalid {name} '{value}'"))
+    }
+}
+
+impl FromStr for Order {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let line = line.trim();
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        let mut arguments = rest.split_whitespace();
+
+        let order = match command {
+            "help" => Order::Help,
+            "list" => Order::List,
+            "kick" => Order::Kick(Self::argument("id", arguments.next())?),
+            "ban" if !rest.is_empty() => Order::Ban(rest.to_string()),
+            "ban" => return Err(String::from("missing name")),
+            "say" if !rest.is_empty() => Order::Say(rest.to_string()),
+            "say" => return Err(String::from("missing message")),
+            "newmap" => Order::NewMap(arguments.next().map(|seed| Self::argument("seed", Some(seed))).transpose()?),
+            "tp" => Order::Teleport(
+                Self::argument("id", arguments.next())?,
+                vec2(Self::argument("x", arguments.next())?, Self::argument("y", arguments.next())?)
+            ),
+            "maxplayers" => Order::MaxPlayers(Self::argument("count", arguments.next())?),
+            "shutdown" => Order::Shutdown,
+            "" => return Err(String::from("empty command")),
+            _ => return Err(format!("unknown command '{command}', type help for the list"))
+        };
+
+        // Names and messages take the whole line
+        if !matches!(order, Order::Ban(_) | Order::Say(_)) && arguments.next().is_some() {
+            return Err(format!("too many arguments for {command}"));
+        }
+
+        Ok(order)
+    }
+}
+
+/// Lines typed on the standard input, read from another thread as reading them blocks
+pub fn stdin_lines() -> Receiver<String> {
+    let (sender, lines) = mpsc::channel();
+
+    thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_are_parsed_from_lines() {
+        assert_eq!("list".parse(), Ok(Order::List));
+        assert_eq!("  kick 42 ".parse(), Ok(Order::Kick(42)));
+        assert_eq!("ban Mister  Bean".parse(), Ok(Order::Ban(String::from("Mister  Bean"))));
+        assert_eq!("say Restarting in 5 minutes".parse(), Ok(Order::Say(String::from("Restarting in 5 minutes"))));
+        assert_eq!("newmap".parse(), Ok(Order::NewMap(None)));
+        assert_eq!("newmap 7".parse(), Ok(Order::NewMap(Some(7))));
+        assert_eq!("tp 3 -10.5 20".parse(), Ok(Order::Teleport(3, vec2(-10.5, 20.0))));
+        assert_eq!("maxplayers 4".parse(), Ok(Order::MaxPlayers(4)));
+        assert_eq!("shutdown".parse(), Ok(Order::Shutdown));
+
+        assert!("kick".parse::<Order>().is_err());
+        assert!("kick Alice".parse::<Order>().is_err());
+        assert!("tp 3 10".parse::<Order>().is_err());
+        assert!("list all".parse::<Order>().is_err());
+        assert!("say".parse::<Order>().is_err());
+        assert!("dance".parse::<Order>().is_err());
+        assert!("".parse::<Order>().is_err());
+    }
+}
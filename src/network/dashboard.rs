@@ -0,0 +1,251 @@
+use macroquad::prelude::*;
+
+use std::time::Duration;
+
+use crate::game::map::{ Map, Room };
+
+/// A line of the client table
+pub struct ClientRow {
+    pub id: usize,
+    pub name: String,
+    /// `None` while the player's slot waits for it to reconnect
+    pub address: Option<String>,
+    pub rtt: Option<Duration>,
+    pub datagrams: bool,
+    pub sent: u64,
+    pub received: u64
+}
+
+/// A player on the map overview
+pub struct Marker {
+    pub id: usize,
+    pub position: Vec2,
+    pub size: Vec2,
+    pub connected: bool
+}
+
+/// What the host view shows of a `GameServer`: an overview of the map that can be zoomed with the wheel
+/// and dragged around (right click resets it), the table of clients, and the log, scrolled with the wheel
+#[derive(Debug)]
+pub struct Dashboard {
+    /// World position shown at the center of the map pane
+    focus: Vec2,
+    /// 1 fits the whole map in the pane
+    zoom: f32,
+    /// Mouse position of the drag in progress
+    dragging: Option<Vec2>,
+    /// Log lines hidden below the pane
+    scroll: usize
+}
+
+impl Default for Dashboard {
+    fn default() -> Self {
+        Self { focus: Vec2::ZERO, zoom: 1.0, dragging: None, scroll: 0 }
+    }
+}
+
+impl Dashboard {
+    const MIN_ZOOM: f32 = 0.5;
+    const MAX_ZOOM: f32 = 64.0;
+    /// Zoom change per notch of the wheel
+    const ZOOM_STEP: f32 = 1.2;
+    const LINE_HEIGHT: f32 = 18.0;
+    const FONT_SIZE: f32 = 16.0;
+    /// Horizontal position of each column of the client table, as a share of the pane
+    const COLUMNS: [(&str, f32); 6] = [("Id", 0.0), ("Name", 0.24), ("Address", 0.44), ("RTT", 0.7), ("In", 0.8), ("Out", 0.9)];
+
+    /// Zooms, drags and scrolls the panes under the mouse
+    pub fn handle_events(&mut self, map: &Map, log_length: usize) {
+        let mouse = Vec2::from(mouse_position());
+        let (_, wheel) = mouse_wheel();
+        let pane = Self::map_pane();
+
+        if pane.contains(mouse) {
+            if wheel != 0.0 {
+                // The point under the mouse stays where it is
+                let anchor = self.to_world(map, mouse);
+                self.zoom = (self.zoom * Self::ZOOM_STEP.powf(wheel.signum())).clamp(Self::MIN_ZOOM, Self::MAX_ZOOM);
+                self.focus += anchor - self.to_world(map, mouse);
+            }
+            if is_mouse_button_pressed(MouseButton::Left) {
+                self.dragging = Some(mouse);
+            }
+            if is_mouse_button_pressed(MouseButton::Right) {
+                *self = Self { scroll: self.scroll, ..Self::default() };
+            }
+        } else if Self::log_pane().contains(mouse) && wheel != 0.0 {
+            let hidden = log_length.saturating_sub(Self::visible_lines());
+            self.scroll = if wheel > 0.0 { (self.scroll + 3).min(hidden) } else { self.scroll.saturating_sub(3) };
+        }
+
+        if let Some(from) = self.dragging {
+            self.focus -= (mouse - from) / self.scale(map);
+            self.dragging = is_mouse_button_down(MouseButton::Left).then_some(mouse);
+        }
+    }
+
+    pub fn draw(&self, map: &Map, markers: &[Marker], clients: &[ClientRow], max_players: usize, log: &[String]) {
+        self.draw_map(map, markers);
+        Self::draw_clients(clients, max_players);
+        self.draw_log(log);
+    }
+
+    fn map_pane() -> Rect {
+        Rect::new(10.0, 10.0, screen_width() * 0.55 - 15.0, screen_height() * 0.6 - 15.0)
+    }
+
+    /// Below the in-game menu's button
+    fn table_pane() -> Rect {
+        Rect::new(screen_width() * 0.55 + 5.0, screen_height() * 0.18, screen_width() * 0.45 - 15.0, screen_height() * 0.42 - 5.0)
+    }
+
+    /// Above the console
+    fn log_pane() -> Rect {
+        Rect::new(10.0, screen_height() * 0.6 + 5.0, screen_width() - 20.0, screen_height() * 0.32 - 10.0)
+    }
+
+    fn visible_lines() -> usize {
+        (Self::log_pane().h / Self::LINE_HEIGHT) as usize
+    }
+
+    /// World area covered by the rooms of `map`
+    fn bounds(map: &Map) -> Rect {
+        Rect::new(
+            -((map.width / 2) as f32) * Room::WIDTH,
+            -((map.height / 2) as f32) * Room::HEIGHT,
+            map.width as f32 * Room::WIDTH,
+            map.height as f32 * Room::HEIGHT
+        )
+    }
+
+    /// Pixels per world unit
+    fn scale(&self, map: &Map) -> f32 {
+        let (pane, bounds) = (Self::map_pane(), Self::bounds(map));
+        self.zoom * (pane.w / bounds.w).min(pane.h / bounds.h)
+    }
+
+    fn origin(&self, map: &Map) -> Vec2 {
+        Self::bounds(map).center() + self.focus
+    }
+
+    fn to_screen(&self, map: &Map, world: Vec2) -> Vec2 {
+        Self::map_pane().center() + (world - self.origin(map)) * self.scale(map)
+    }
+
+    fn to_world(&self, map: &Map, screen: Vec2) -> Vec2 {
+        self.origin(map) + (screen - Self::map_pane().center()) / self.scale(map)
+    }
+
+    /// Draws the part of `world` that is inside the map pane
+    fn fill(&self, map: &Map, world: Rect, color: Color) {
+        let corner = self.to_screen(map, world.point());
+        let scale = self.scale(map);
+        if let Some(shown) = Rect::new(corner.x, corner.y, world.w * scale, world.h * scale).intersect(Self::map_pane()) {
+            draw_rectangle(shown.x, shown.y, shown.w, shown.h, color);
+        }
+    }
+
+    fn draw_map(&self, map: &Map, markers: &[Marker]) {
+        let pane = Self::map_pane();
+        draw_rectangle(pane.x, pane.y, pane.w, pane.h, Color::from_rgba(20, 20, 30, 255));
+
+        // Only the rooms in sight are drawn, there may be a million of them
+        let (top, left) = map.cell_of(self.to_world(map, pane.point()));
+        let (bottom, right) = map.cell_of(self.to_world(map, pane.point() + pane.size()));
+        for line in top.max(0)..=bottom.min(map.height as i32 - 1) {
+            for column in left.max(0)..=right.min(map.width as i32 - 1) {
+                let Some(room) = map.get_room(line, column) else { continue };
+
+                let corner = vec2(
+                    (column - map.width as i32 / 2) as f32 * Room::WIDTH,
+                    (line - map.height as i32 / 2) as f32 * Room::HEIGHT
+                );
+                self.fill(map, Rect::new(corner.x, corner.y, Room::WIDTH, Room::HEIGHT), Color::from_rgba(45, 45, 60, 255));
+                for wall in room.components.iter().flatten() {
+                    let body = wall.body();
+                    self.fill(map, Rect::new(body.position.x, body.position.y, body.size.x, body.size.y), MAROON);
+                }
+            }
+        }
+
+        for marker in markers {
+            let center = self.to_screen(map, marker.position + marker.size / 2.0);
+            if !pane.contains(center) {
+                continue;
+            }
+
+            let radius = (marker.size.x * self.scale(map) / 2.0).max(4.0);
+            draw_circle(center.x, center.y, radius, if marker.connected { SKYBLUE } else { GRAY });
+            draw_text(marker.id.to_string(), center.x + radius + 2.0, center.y - radius, 16.0, WHITE);
+        }
+
+        draw_rectangle_lines(pane.x, pane.y, pane.w, pane.h, 2.0, GRAY);
+        draw_text(format!("x{:.1}", self.zoom), pane.x + 6.0, pane.y + pane.h - 6.0, 16.0, GRAY);
+    }
+
+    fn draw_clients(clients: &[ClientRow], max_players: usize) {
+        let pane = Self::table_pane();
+        draw_rectangle(pane.x, pane.y, pane.w, pane.h, Color::from_rgba(20, 20, 30, 255));
+        draw_rectangle_lines(pane.x, pane.y, pane.w, pane.h, 2.0, GRAY);
+
+        let column = |index: usize| pane.x + 6.0 + pane.w * Self::COLUMNS[index].1;
+        let mut y = pane.y + Self::LINE_HEIGHT;
+        draw_text(format!("Players: {}/{max_players}", clients.len()), column(0), y, Self::FONT_SIZE, WHITE);
+
+        y += Self::LINE_HEIGHT * 1.5;
+        for (index, (title, _)) in Self::COLUMNS.iter().enumerate() {
+            draw_text(title, column(index), y, Self::FONT_SIZE, LIGHTGRAY);
+        }
+
+        for client in clients {
+            y += Self::LINE_HEIGHT;
+            if y > pane.y + pane.h {
+                break;
+            }
+
+            let cells = [
+                client.id.to_string(),
+                client.name.clone(),
+                client.address.clone().unwrap_or(String::from("reconnecting...")),
+                client.rtt.map_or(String::from("-"), |rtt| format!("{} ms", rtt.as_millis())),
+                Self::bytes(client.received),
+                Self::bytes(client.sent)
+            ];
+            let color = match (&client.address, client.datagrams) {
+                (None, _) => GRAY,
+                (Some(_), true) => WHITE,
+                // Still on TCP, either it just joined or its datagrams do not get through
+                (Some(_), false) => YELLOW
+            };
+            for (index, cell) in cells.iter().enumerate() {
+                draw_text(cell, column(index), y, Self::FONT_SIZE, color);
+            }
+        }
+    }
+
+    fn draw_log(&self, log: &[String]) {
+        let pane = Self::log_pane();
+        draw_rectangle(pane.x, pane.y, pane.w, pane.h, Color::from_rgba(10, 10, 15, 255));
+        draw_rectangle_lines(pane.x, pane.y, pane.w, pane.h, 2.0, GRAY);
+
+        let end = log.len().saturating_sub(self.scroll);
+        let shown = &log[end.saturating_sub(Self::visible_lines())..end];
+        for (index, line) in shown.iter().enumerate() {
+            draw_text(line, pane.x + 6.0, pane.y + Self::LINE_HEIGHT * (index + 1) as f32 - 4.0, Self::FONT_SIZE, LIGHTGRAY);
+        }
+
+        if self.scroll > 0 {
+            let notice = format!("{} newer lines", self.scroll);
+            let width = measure_text(&notice, None, 16, 1.0).width;
+            draw_text(&notice, pane.x + pane.w - width - 6.0, pane.y + pane.h - 6.0, 16.0, YELLOW);
+        }
+    }
+
+    fn bytes(count: u64) -> String {
+        match count {
+            0..1_024 => format!("{count} B"),
+            1_024..1_048_576 => format!("{:.1} KiB", count as f64 / 1_024.0),
+            _ => format!("{:.1} MiB", count as f64 / 1_048_576.0)
+        }
+    }
+}
@@ -0,0 +1,522 @@
+use std::collections::{ HashMap, VecDeque };
+use std::io::{ self, ErrorKind };
+use std::mem::Discriminant;
+use std::net::UdpSocket;
+use std::time::{ Duration, Instant };
+
+use auto_with::with;
+
+use super::codec::{ Reader, Shareable };
+use super::rate::TokenBucket;
+use super::sequence::Sequence;
+use super::{ Command, ProtocolError, ProtocolStats };
+
+/// Delivery guarantees of a command sent through a `DatagramProtocol`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Channel {
+    /// Lost datagrams are not resent, and those older than the newest received
+    /// for the same `Stream` are dropped
+    UnreliableSequenced,
+    /// Every command is delivered once, in the order it was sent
+    ReliableOrdered
+}
+
+impl Channel {
+    const UNRELIABLE: u8 = 0;
+    const RELIABLE: u8 = 1;
+    /// Datagrams carrying no command, only an acknowledgement
+    const ACKNOWLEDGEMENT: u8 = 2;
+
+    /// States that are sent continuously go unreliable, since a newer one is always on its way
+    pub fn of(command: &Command) -> Self {
+        match command {
+            Command::Update(_, _, delta) if delta.is_motion() => Channel::UnreliableSequenced,
+            Command::Input(..)
+            | Command::Acknowledge { .. }
+            | Command::Snapshot(_)
+            | Command::SnapshotAck(_)
+            | Command::Ping(_)
+            | Command::Pong(_) => Channel::UnreliableSequenced,
+            _ => Channel::ReliableOrdered
+        }
+    }
+}
+
+/// Unreliable commands superseding each other: the updates of an entity, or the commands of a kind
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Stream {
+    Entity(usize),
+    Kind(Discriminant<Command>)
+}
+
+impl Stream {
+    fn of(command: &Command) -> Self {
+        match command {
+            Command::Update(id, ..) => Stream::Entity(*id),
+            command => Stream::Kind(std::mem::discriminant(command))
+        }
+    }
+}
+
+/// Something datagrams are exchanged through, with a single peer
+pub trait Datagrams {
+    fn send_datagram(&self, datagram: &[u8]) -> io::Result<()>;
+    fn receive_datagram(&self, buffer: &mut [u8]) -> io::Result<usize>;
+}
+
+/// The socket must be connected to the peer
+impl Datagrams for UdpSocket {
+    fn send_datagram(&self, datagram: &[u8]) -> io::Result<()> {
+        self.send(datagram).map(|_| ())
+    }
+
+    fn receive_datagram(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.recv(buffer)
+    }
+}
+
+/// A reliable command waiting for its acknowledgement
+#[derive(Debug)]
+struct Unacknowledged {
+    sequence: Sequence,
+    body: Vec<u8>,
+    sent: Instant
+}
+
+/// Counterpart of `Protocol` over datagrams, one command per datagram.
+/// Datagrams are laid out as `[channel: u8][sequence: u32][acknowledgement: u32][command]`, where
+/// `sequence` counts the datagrams of the channel and `acknowledgement` is the last reliable
+/// command received in order. Both channels share the heartbeats and idle timeout of `Protocol`.
+#[derive(Debug)]
+pub struct DatagramProtocol {
+    /// Newest unreliable datagram received, to count the lost ones
+    last_unreliable_reception: Sequence,
+    /// Newest unreliable datagram received for each stream, older ones being stale
+    last_stream_receptions: HashMap<Stream, Sequence>,
+    last_unreliable_send: Sequence,
+    /// Last reliable command delivered, everything before it was received
+    last_reliable_reception: Sequence,
+    last_reliable_send: Sequence,
+    /// Reliable commands received ahead of a missing one
+    out_of_order: HashMap<Sequence, Command>,
+    unacknowledged: VecDeque<Unacknowledged>,
+    acknowledgement_due: bool,
+    delivered: VecDeque<Command>,
+    stats: ProtocolStats,
+
+    created: Instant,
+    last_heard: Instant,
+    last_ping: Option<Instant>,
+    pongs_due: Vec<u64>,
+    pings: TokenBucket,
+    ping_interval: Duration,
+    idle_timeout: Duration
+}
+
+impl Default for DatagramProtocol {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DatagramProtocol {
+    /// Datagrams larger than this may be fragmented, or dropped, on the way
+    pub const MAX_SIZE: usize = 1200;
+
+    const HEADER_SIZE: usize = 9;
+    /// Reliable commands held while waiting for a missing one
+    const MAX_OUT_OF_ORDER: usize = 256;
+    /// Resend delay until a round trip time is measured
+    const RESEND_DELAY: Duration = Duration::from_millis(200);
+    const MIN_RESEND_DELAY: Duration = Duration::from_millis(30);
+
+    /// Whether `command` can be sent without exceeding `MAX_SIZE`
+    pub fn fits(command: &Command) -> bool {
+        Self::HEADER_SIZE + command.as_bytes().len() <= Self::MAX_SIZE
+    }
+
+    pub fn new() -> Self {
+        Self {
+            last_unreliable_reception: Sequence::default(),
+            last_stream_receptions: HashMap::new(),
+            last_unreliable_send: Sequence::default(),
+            last_reliable_reception: Sequence::default(),
+            last_reliable_send: Sequence::default(),
+            out_of_order: HashMap::new(),
+            unacknowledged: VecDeque::new(),
+            acknowledgement_due: false,
+            delivered: VecDeque::new(),
+            stats: ProtocolStats::default(),
+            created: Instant::now(),
+            last_heard: Instant::now(),
+            last_ping: None,
+            pongs_due: Vec::new(),
+            pings: TokenBucket::new(super::Protocol::PING_BURST, super::Protocol::PING_RATE),
+            ping_interval: super::Protocol::DEFAULT_PING_INTERVAL,
+            idle_timeout: super::Protocol::DEFAULT_IDLE_TIMEOUT
+        }
+    }
+
+    with!{ idle_timeout: Duration }
+
+    pub fn stats(&self) -> ProtocolStats {
+        self.stats
+    }
+
+    /// Number of reliable commands the peer did not acknowledge yet
+    pub fn unacknowledged(&self) -> u32 {
+        self.unacknowledged.len() as u32
+    }
+
+    /// Returns the next command delivered by either channel.
+    /// `ProtocolError::Pending` means no datagram is waiting on the (non-blocking) socket.
+    pub fn reception(&mut self, socket: &impl Datagrams) -> Result<Command, ProtocolError> {
+        loop {
+            if let Some(command) = self.delivered() {
+                return Ok(command);
+            }
+
+            let mut buffer = [0u8; Self::MAX_SIZE];
+            match socket.receive_datagram(&mut buffer) {
+                Ok(size) => self.feed(&buffer[..size])?,
+                Err(e) => match e.kind() {
+                    ErrorKind::Interrupted => {},
+                    // A peer that is not listening yet is not a disconnection, the idle timeout decides
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::ConnectionRefused => return Err(ProtocolError::Pending),
+                    _ => return Err(ProtocolError::WrongSequence)
+                }
+            }
+        }
+    }
+
+    pub fn send(&mut self, socket: &impl Datagrams, command: Command) -> io::Result<()> {
+        let body = command.as_bytes();
+
+        match Channel::of(&command) {
+            Channel::UnreliableSequenced => {
+                self.last_unreliable_send = self.last_unreliable_send.next();
+                self.transmit(socket, Channel::UNRELIABLE, self.last_unreliable_send, &body)
+            },
+            Channel::ReliableOrdered => {
+                self.last_reliable_send = self.last_reliable_send.next();
+                let sequence = self.last_reliable_send;
+                self.unacknowledged.push_back(Unacknowledged { sequence, body: body.clone(), sent: Instant::now() });
+                self.transmit(socket, Channel::RELIABLE, sequence, &body)
+            }
+        }
+    }
+
+    /// Same as `Protocol::heartbeat`, and also resends the reliable commands
+    /// the peer did not acknowledge in time and acknowledges those it sent
+    pub fn heartbeat(&mut self, socket: &impl Datagrams) -> Result<(), ProtocolError> {
+        if self.last_heard.elapsed() > self.idle_timeout {
+            return Err(ProtocolError::Disconnection);
+        }
+
+        for time in std::mem::take(&mut self.pongs_due) {
+            let _ = self.send(socket, Command::Pong(time));
+        }
+        if self.last_ping.is_none_or(|last| last.elapsed() >= self.ping_interval) {
+            self.last_ping = Some(Instant::now());
+            let _ = self.send(socket, Command::Ping(self.clock()));
+        }
+
+        let delay = self.stats.rtt.map_or(Self::RESEND_DELAY, |rtt| (rtt * 2).max(Self::MIN_RESEND_DELAY));
+        let mut overdue = Vec::new();
+        for pending in self.unacknowledged.iter_mut().filter(|pending| pending.sent.elapsed() >= delay) {
+            pending.sent = Instant::now();
+            overdue.push((pending.sequence, pending.body.clone()));
+        }
+        for (sequence, body) in overdue {
+            self.stats.resent += 1;
+            let _ = self.transmit(socket, Channel::RELIABLE, sequence, &body);
+        }
+
+        if self.acknowledgement_due {
+            let _ = self.transmit(socket, Channel::ACKNOWLEDGEMENT, Sequence::default(), &[]);
+        }
+
+        Ok(())
+    }
+
+    /// Next command received, without reading the socket
+    pub fn delivered(&mut self) -> Option<Command> {
+        self.delivered.pop_front()
+    }
+
+    /// Processes a datagram received outside of `reception`, see `delivered`
+    pub fn feed(&mut self, datagram: &[u8]) -> Result<(), ProtocolError> {
+        self.stats.bytes_received += datagram.len() as u64;
+        if datagram.len() < Self::HEADER_SIZE {
+            self.stats.malformed += 1;
+            return Err(ProtocolError::IllFormatedSequenceNumber);
+        }
+
+        let mut header = Reader::new(&datagram[..Self::HEADER_SIZE]);
+        let (channel, sequence, acknowledgement) = match (header.read::<u8>(), header.read::<Sequence>(), header.read::<Sequence>()) {
+            (Ok(channel), Ok(sequence), Ok(acknowledgement)) => (channel, sequence, acknowledgement),
+            _ => {
+                self.stats.malformed += 1;
+                return Err(ProtocolError::IllFormatedSequenceNumber);
+            }
+        };
+        let body = &datagram[Self::HEADER_SIZE..];
+
+        self.stats.received += 1;
+        self.last_heard = Instant::now();
+
+        // Acknowledgements of commands we never sent are ignored
+        if !acknowledgement.is_newer_than(self.last_reliable_send) {
+            while self.unacknowledged.front().is_some_and(|pending| !pending.sequence.is_newer_than(acknowledgement)) {
+                self.unacknowledged.pop_front();
+            }
+        }
+
+        match channel {
+            Channel::UNRELIABLE => {
+                let command = self.decode(body);
+
+                let stream = Stream::of(&command);
+                let last = self.last_stream_receptions.get(&stream).copied().unwrap_or_default();
+                if !sequence.is_newer_than(last) {
+                    self.stats.duplicated += 1;
+                    return Err(ProtocolError::OutdatedPackage);
+                }
+                self.last_stream_receptions.insert(stream, sequence);
+
+                if sequence.is_newer_than(self.last_unreliable_reception) {
+                    self.stats.lost += (sequence.distance_from(self.last_unreliable_reception) - 1) as u64;
+                    self.last_unreliable_reception = sequence;
+                } else {
+                    // Late, but newer than anything else of its stream: it was counted as lost
+                    self.stats.lost = self.stats.lost.saturating_sub(1);
+                }
+
+                match command {
+                    Command::Ping(_) if !self.pings.take() => return Err(ProtocolError::TooManyPings),
+                    Command::Ping(time) => self.pongs_due.push(time),
+                    Command::Pong(time) => self.stats.observe_rtt(Duration::from_millis(self.clock().saturating_sub(time))),
+                    command => self.delivered.push_back(command)
+                }
+            },
+            Channel::RELIABLE => {
+                self.acknowledgement_due = true;
+
+                if !sequence.is_newer_than(self.last_reliable_reception) {
+                    self.stats.duplicated += 1;
+                    return Err(ProtocolError::OutdatedPackage);
+                }
+
+                if sequence == self.last_reliable_reception.next() {
+                    self.last_reliable_reception = sequence;
+                    let command = self.decode(body);
+                    self.deliver_reliable(command);
+
+                    while let Some(command) = self.out_of_order.remove(&self.last_reliable_reception.next()) {
+                        self.last_reliable_reception = self.last_reliable_reception.next();
+                        self.deliver_reliable(command);
+                    }
+                } else if self.out_of_order.len() < Self::MAX_OUT_OF_ORDER {
+                    let command = self.decode(body);
+                    self.out_of_order.insert(sequence, command);
+                }
+            },
+            Channel::ACKNOWLEDGEMENT => {},
+            _ => {
+                self.stats.malformed += 1;
+                return Err(ProtocolError::IllFormatedSequenceNumber);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn deliver_reliable(&mut self, command: Command) {
+        // Entities that are gone do not need their stream anymore
+        if let Command::Despawn(id) = command {
+            self.last_stream_receptions.remove(&Stream::Entity(id));
+        }
+        self.delivered.push_back(command);
+    }
+
+    fn decode(&mut self, body: &[u8]) -> Command {
+        let command = Command::from(body);
+        if let Command::Unknown | Command::IllFormated(_) = command {
+            self.stats.malformed += 1;
+        }
+        command
+    }
+
+    fn transmit(&mut self, socket: &impl Datagrams, channel: u8, sequence: Sequence, body: &[u8]) -> io::Result<()> {
+        let mut datagram = Vec::with_capacity(Self::HEADER_SIZE + body.len());
+        channel.encode(&mut datagram);
+        sequence.encode(&mut datagram);
+        self.last_reliable_reception.encode(&mut datagram);
+        datagram.extend_from_slice(body);
+
+        self.acknowledgement_due = false;
+        self.stats.sent += 1;
+        self.stats.bytes_sent += datagram.len() as u64;
+        socket.send_datagram(&datagram)
+    }
+
+    /// Milliseconds since this protocol was created
+    fn clock(&self) -> u64 {
+        self.created.elapsed().as_millis() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::Cell;
+
+    use macroquad::prelude::*;
+
+    use crate::network::Protocol;
+    use crate::network::replication::EntityDelta;
+
+    /// Loopback socket losing every `period`-th datagram it sends
+    struct Lossy {
+        socket: UdpSocket,
+        period: usize,
+        count: Cell<usize>
+    }
+
+    impl Datagrams for Lossy {
+        fn send_datagram(&self, datagram: &[u8]) -> io::Result<()> {
+            self.count.set(self.count.get() + 1);
+            if self.count.get().is_multiple_of(self.period) {
+                return Ok(());
+            }
+            self.socket.send_datagram(datagram)
+        }
+
+        fn receive_datagram(&self, buffer: &mut [u8]) -> io::Result<usize> {
+            self.socket.receive_datagram(buffer)
+        }
+    }
+
+    fn pair() -> (UdpSocket, UdpSocket) {
+        let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        a.connect(b.local_addr().unwrap()).unwrap();
+        b.connect(a.local_addr().unwrap()).unwrap();
+        a.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+        b.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+        (a, b)
+    }
+
+    fn drain(protocol: &mut DatagramProtocol, socket: &impl Datagrams) -> Vec<Command> {
+        let mut received = Vec::new();
+        loop {
+            match protocol.reception(socket) {
+                Ok(command) => received.push(command),
+                Err(ProtocolError::Pending) => return received,
+                Err(_) => {}
+            }
+        }
+    }
+
+    #[test]
+    fn reliable_commands_survive_losses_in_order() {
+        let (a, b) = pair();
+        let a = Lossy { socket: a, period: 3, count: Cell::new(0) };
+        let (mut sender, mut receiver) = (DatagramProtocol::new(), DatagramProtocol::new());
+
+        for id in 0..20 {
+            sender.send(&a, Command::Despawn(id)).unwrap();
+        }
+
+        let mut received = Vec::new();
+        for _ in 0..100 {
+            received.extend(drain(&mut receiver, &b));
+            receiver.heartbeat(&b).unwrap();
+            drain(&mut sender, &a);
+            sender.heartbeat(&a).unwrap();
+            if sender.unacknowledged() == 0 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        received.extend(drain(&mut receiver, &b));
+
+        assert_eq!(received, (0..20).map(Command::Despawn).collect::<Vec<_>>());
+        assert_eq!(sender.unacknowledged(), 0);
+        assert!(sender.stats().resent > 0);
+    }
+
+    #[test]
+    fn stale_positions_are_dropped() {
+        let (a, b) = pair();
+        let (mut sender, mut receiver) = (DatagramProtocol::new(), DatagramProtocol::new());
+
+        sender.send(&a, Command::Update(1, 10, EntityDelta::moved(vec2(1.0, 0.0)))).unwrap();
+        sender.send(&a, Command::Update(1, 20, EntityDelta::moved(vec2(2.0, 0.0)))).unwrap();
+        assert_eq!(drain(&mut receiver, &b).len(), 2);
+
+        // An older position arriving late, then a newer one after a lost datagram
+        let mut late = Vec::new();
+        Channel::UNRELIABLE.encode(&mut late);
+        Sequence(1).encode(&mut late);
+        Sequence(0).encode(&mut late);
+        late.extend(Command::Update(1, 0, EntityDelta::moved(vec2(0.0, 0.0))).as_bytes());
+        assert_eq!(receiver.feed(&late), Err(ProtocolError::OutdatedPackage));
+
+        sender.last_unreliable_send = sender.last_unreliable_send.next();
+        sender.send(&a, Command::Update(1, 40, EntityDelta::moved(vec2(4.0, 0.0)))).unwrap();
+        assert_eq!(drain(&mut receiver, &b), vec![Command::Update(1, 40, EntityDelta::moved(vec2(4.0, 0.0)))]);
+
+        let stats = receiver.stats();
+        assert_eq!((stats.duplicated, stats.lost), (1, 1));
+    }
+
+    #[test]
+    fn pings_are_limited() {
+        let (a, b) = pair();
+        let (mut sender, mut receiver) = (DatagramProtocol::new(), DatagramProtocol::new());
+
+        for time in 0..20 {
+            sender.send(&a, Command::Ping(time)).unwrap();
+        }
+
+        let mut refused = 0;
+        loop {
+            match receiver.reception(&b) {
+                Err(ProtocolError::Pending) => break,
+                Err(ProtocolError::TooManyPings) => refused += 1,
+                _ => {}
+            }
+        }
+        assert_eq!(refused, 20 - Protocol::PING_BURST);
+    }
+
+    #[test]
+    fn streams_are_sequenced_separately() {
+        let unreliable = |sequence: u32, command: Command| {
+            let mut datagram = Vec::new();
+            Channel::UNRELIABLE.encode(&mut datagram);
+            Sequence(sequence).encode(&mut datagram);
+            Sequence(0).encode(&mut datagram);
+            datagram.extend(command.as_bytes());
+            datagram
+        };
+        let mut receiver = DatagramProtocol::new();
+
+        // The update of entity 1 was overtaken by those of another entity and by a heartbeat
+        receiver.feed(&unreliable(3, Command::Update(2, 30, EntityDelta::moved(vec2(3.0, 0.0))))).unwrap();
+        receiver.feed(&unreliable(4, Command::Pong(0))).unwrap();
+        assert_eq!(receiver.stats().lost, 2);
+        receiver.feed(&unreliable(2, Command::Update(1, 20, EntityDelta::moved(vec2(2.0, 0.0))))).unwrap();
+        assert_eq!(receiver.delivered(), Some(Command::Update(2, 30, EntityDelta::moved(vec2(3.0, 0.0)))));
+        assert_eq!(receiver.delivered(), Some(Command::Update(1, 20, EntityDelta::moved(vec2(2.0, 0.0)))));
+
+        // Still stale for its own entity
+        let stale = unreliable(1, Command::Update(2, 10, EntityDelta::moved(vec2(1.0, 0.0))));
+        assert_eq!(receiver.feed(&stale), Err(ProtocolError::OutdatedPackage));
+
+        let stats = receiver.stats();
+        assert_eq!((stats.duplicated, stats.lost), (1, 1));
+    }
+}
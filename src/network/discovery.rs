@@ -0,0 +1,186 @@
+use std::io;
+use std::net::{ Ipv4Addr, SocketAddr, UdpSocket };
+use std::time::{ Duration, Instant };
+
+use super::codec::{ Reader, Shareable };
+use super::FormatError;
+
+/// What a server tells the local network about itself
+#[derive(Clone, Debug, PartialEq)]
+pub struct Announcement {
+    pub name: String,
+    pub players: usize,
+    pub max_players: usize,
+    pub seed: usize,
+    /// `Protocol::VERSION` of the server, it only accepts clients speaking the same
+    pub version: u16,
+    /// TCP port to connect to, on the address the announcement came from
+    pub port: u16
+}
+
+impl Announcement {
+    /// Leads every announcement, so that other datagrams sent to the port are ignored
+    const MAGIC: &[u8] = b"BORED";
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Self::MAGIC.to_vec();
+        self.encode(&mut bytes);
+        bytes
+    }
+
+    pub fn decode_datagram(datagram: &[u8]) -> Result<Self, FormatError> {
+        let body = datagram.strip_prefix(Self::MAGIC).ok_or(FormatError::InvalidValue)?;
+        let mut reader = Reader::new(body);
+        let announcement = reader.read()?;
+        reader.finish()?;
+        Ok(announcement)
+    }
+}
+
+/// Broadcasts a server's `Announcement` on the local network, see `Scanner`
+pub struct Beacon {
+    socket: UdpSocket,
+    target: SocketAddr,
+    last: Option<Instant>
+}
+
+impl Beacon {
+    /// Port scanners listen on
+    pub const PORT: u16 = 53_001;
+    const INTERVAL: Duration = Duration::from_secs(1);
+
+    /// Announces to every machine of the local network
+    pub fn new() -> io::Result<Self> {
+        Self::to(SocketAddr::from((Ipv4Addr::BROADCAST, Self::PORT)))
+    }
+
+    /// Announces to a single scanner, such as one on the loopback
+    pub fn to(target: SocketAddr) -> io::Result<Self> {
+        let socket = UdpSocket::bind(if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+        socket.set_broadcast(true)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket, target, last: None })
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.last.is_none_or(|last| last.elapsed() >= Self::INTERVAL)
+    }
+
+    pub fn announce(&mut self, announcement: &Announcement) {
+        self.last = Some(Instant::now());
+        // Networks without broadcast only lose discovery
+        let _ = self.socket.send_to(&announcement.as_bytes(), self.target);
+    }
+}
+
+/// A server heard of by a `Scanner`
+#[derive(Clone, Debug)]
+pub struct Discovered {
+    /// Where to connect to
+    pub address: SocketAddr,
+    pub announcement: Announcement,
+    pub last_heard: Instant
+}
+
+/// Collects the `Announcement`s of the servers on the local network
+pub struct Scanner {
+    socket: UdpSocket,
+    servers: Vec<Discovered>
+}
+
+impl Scanner {
+    /// Servers that stopped announcing themselves for this long are forgotten
+    const TIMEOUT: Duration = Duration::from_secs(3);
+
+    /// Listens to the beacons of the local network
+    pub fn new() -> io::Result<Self> {
+        Self::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, Beacon::PORT)))
+    }
+
+    pub fn bind(address: SocketAddr) -> io::Result<Self> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket, servers: Vec::new() })
+    }
+
+    pub fn local_address(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Reads the announcements received since the last call, and forgets the servers that went silent
+    pub fn poll(&mut self) {
+        let mut buffer = [0u8; 512];
+        while let Ok((size, sender)) = self.socket.recv_from(&mut buffer) {
+            let Ok(announcement) = Announcement::decode_datagram(&buffer[..size]) else { continue };
+            let address = SocketAddr::new(sender.ip(), announcement.port);
+
+            let discovered = Discovered { address, announcement, last_heard: Instant::now() };
+            match self.servers.iter_mut().find(|server| server.address == address) {
+                Some(known) => *known = discovered,
+                None => self.servers.push(discovered)
+            }
+        }
+
+        self.servers.retain(|server| server.last_heard.elapsed() < Self::TIMEOUT);
+    }
+
+    /// Servers heard of recently, in the order they were first heard of
+    pub fn servers(&self) -> &[Discovered] {
+        &self.servers
+    }
+
+    /// Forgets every server, those still running are heard of again within a second
+    pub fn refresh(&mut self) {
+        self.servers.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::network::server::GameServer;
+    use crate::network::Protocol;
+    use crate::utils::Dynamic;
+
+    #[test]
+    fn servers_are_discovered_on_the_loopback() {
+        let mut scanner = Scanner::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap();
+        let beacon = Beacon::to(scanner.local_address().unwrap()).unwrap();
+
+        let mut server = GameServer::new("127.0.0.1:0")
+            .unwrap()
+            .with_name(String::from("Dungeon of Alice"))
+            .with_map(42, 3, 3)
+            .with_beacon(beacon);
+        let port = server.address().unwrap().port();
+
+        // Other traffic on the port is ignored
+        let stranger = UdpSocket::bind("127.0.0.1:0").unwrap();
+        stranger.send_to(b"BORED?", scanner.local_address().unwrap()).unwrap();
+        stranger.send_to(b"hello", scanner.local_address().unwrap()).unwrap();
+
+        for _ in 0..500 {
+            server.update();
+            scanner.poll();
+            if !scanner.servers().is_empty() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        let [discovered] = scanner.servers() else { panic!("Expected a single server, found {:?}", scanner.servers()) };
+        assert_eq!(discovered.address, SocketAddr::from((Ipv4Addr::LOCALHOST, port)));
+        assert_eq!(discovered.announcement, Announcement {
+            name: String::from("Dungeon of Alice"),
+            players: 0,
+            max_players: GameServer::DEFAULT_MAX_PLAYERS,
+            seed: 42,
+            version: Protocol::VERSION,
+            port
+        });
+
+        scanner.refresh();
+        assert!(scanner.servers().is_empty());
+    }
+}
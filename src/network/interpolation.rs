@@ -0,0 +1,158 @@
+use macroquad::prelude::*;
+
+use std::collections::VecDeque;
+use std::time::{ Duration, Instant };
+
+/// How remote entities are rendered behind the server clock, see `GameClient::with_interpolation`
+#[derive(Debug, Clone, Copy)]
+pub struct Interpolation {
+    /// Remote entities are shown this far in the past, so that there is
+    /// almost always a snapshot on each side of the rendered instant
+    pub delay: Duration,
+    /// How long an entity keeps moving on its own when its snapshots are late
+    pub max_extrapolation: Duration
+}
+
+impl Default for Interpolation {
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_millis(100),
+            max_extrapolation: Duration::from_millis(250)
+        }
+    }
+}
+
+impl Interpolation {
+    const DELAY_VARIABLE: &str = "DUNGEONS_INTERPOLATION_DELAY_MS";
+    const EXTRAPOLATION_VARIABLE: &str = "DUNGEONS_MAX_EXTRAPOLATION_MS";
+
+    /// Default settings, overridable with the `DUNGEONS_INTERPOLATION_DELAY_MS`
+    /// and `DUNGEONS_MAX_EXTRAPOLATION_MS` variables
+    pub fn from_env() -> Self {
+        let milliseconds = |variable| std::env::var(variable).ok().and_then(|x| x.parse().ok()).map(Duration::from_millis);
+        let mut interpolation = Self::default();
+
+        if let Some(delay) = milliseconds(Self::DELAY_VARIABLE) {
+            interpolation.delay = delay;
+        }
+        if let Some(max_extrapolation) = milliseconds(Self::EXTRAPOLATION_VARIABLE) {
+            interpolation.max_extrapolation = max_extrapolation;
+        }
+
+        interpolation
+    }
+}
+
+/// Estimate of the server clock, built from the timestamps it sends
+#[derive(Debug)]
+pub struct ServerClock {
+    origin: Instant,
+    /// Server time minus local time, in seconds
+    offset: Option<f64>
+}
+
+impl Default for ServerClock {
+    fn default() -> Self {
+        Self { origin: Instant::now(), offset: None }
+    }
+}
+
+impl ServerClock {
+    /// Weight of a new observation in the offset estimate
+    const SMOOTHING: f64 = 0.05;
+
+    pub fn observe(&mut self, server_time: f64) {
+        let sample = server_time - self.local();
+
+        self.offset = Some(match self.offset {
+            Some(offset) => offset + (sample - offset) * Self::SMOOTHING,
+            None => sample
+        });
+    }
+
+    /// Current server time, in seconds
+    pub fn now(&self) -> f64 {
+        self.local() + self.offset.unwrap_or_default()
+    }
+
+    fn local(&self) -> f64 {
+        self.origin.elapsed().as_secs_f64()
+    }
+}
+
+/// Timestamped positions of a remote entity
+#[derive(Debug, Default)]
+pub struct SnapshotBuffer {
+    snapshots: VecDeque<(f64, Vec2)>
+}
+
+impl SnapshotBuffer {
+    const CAPACITY: usize = 32;
+
+    /// Records the position the entity had at server `time`, ignoring snapshots received out of order
+    pub fn push(&mut self, time: f64, position: Vec2) {
+        if self.snapshots.back().is_some_and(|(last, _)| *last >= time) {
+            return;
+        }
+
+        if self.snapshots.len() == Self::CAPACITY {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back((time, position));
+    }
+
+    /// Position of the entity at server `time`, extrapolated for at most
+    /// `max_extrapolation` seconds past the newest snapshot
+    pub fn sample(&self, time: f64, max_extrapolation: f64) -> Option<Vec2> {
+        let (newest_time, newest) = *self.snapshots.back()?;
+
+        if time >= newest_time {
+            return Some(match self.snapshots.iter().rev().nth(1) {
+                Some((previous_time, previous)) => {
+                    let velocity = (newest - *previous) / (newest_time - previous_time) as f32;
+                    newest + velocity * (time - newest_time).min(max_extrapolation) as f32
+                },
+                None => newest
+            });
+        }
+
+        let after = self.snapshots.iter().position(|(t, _)| *t > time)?;
+        if after == 0 {
+            return Some(self.snapshots[0].1);
+        }
+
+        let (t0, p0) = self.snapshots[after - 1];
+        let (t1, p1) = self.snapshots[after];
+        Some(p0.lerp(p1, ((time - t0) / (t1 - t0)) as f32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolates_between_snapshots() {
+        let mut buffer = SnapshotBuffer::default();
+        assert_eq!(buffer.sample(0.0, 0.25), None);
+
+        buffer.push(1.0, vec2(0.0, 0.0));
+        buffer.push(2.0, vec2(10.0, 20.0));
+        // Late packet, already superseded
+        buffer.push(1.5, vec2(100.0, 100.0));
+
+        assert_eq!(buffer.sample(0.5, 0.25), Some(vec2(0.0, 0.0)));
+        assert_eq!(buffer.sample(1.5, 0.25), Some(vec2(5.0, 10.0)));
+        assert_eq!(buffer.sample(2.0, 0.25), Some(vec2(10.0, 20.0)));
+    }
+
+    #[test]
+    fn extrapolation_is_bounded() {
+        let mut buffer = SnapshotBuffer::default();
+        buffer.push(1.0, vec2(0.0, 0.0));
+        buffer.push(2.0, vec2(10.0, 0.0));
+
+        assert_eq!(buffer.sample(2.1, 0.25), Some(vec2(11.0, 0.0)));
+        assert_eq!(buffer.sample(5.0, 0.25), Some(vec2(12.5, 0.0)));
+    }
+}
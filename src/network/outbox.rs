@@ -0,0 +1,164 @@
+use std::collections::VecDeque;
+
+use auto_with::with;
+
+use super::Command;
+
+/// Queue depth counters of an `Outbox`
+#[derive(Copy, Clone, Debug, Default)]
+pub struct OutboxStats {
+    pub depth: usize,
+    /// Deepest the queue has been
+    pub peak: usize,
+    /// Commands replaced by a newer one before being sent
+    pub coalesced: u64,
+    /// Commands dropped to make room, with `Overflow::DropOldest`
+    pub dropped: u64
+}
+
+/// What an `Outbox` does once it is full
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Overflow {
+    /// Empties itself and refuses any other command, the peer has to be sent a fresh state
+    #[default]
+    Close,
+    /// Drops its oldest command, for peers that only care about recent ones
+    DropOldest
+}
+
+/// Commands waiting to be sent to a peer, filled by the game and drained by the thread serving the connection.
+///
+/// When the peer reads slower than the game writes, pending positions are coalesced and
+/// only the newest one of each entity is sent. If the queue still fills up, it overflows: with
+/// `Overflow::Close` it is emptied, stops accepting commands, and the peer has to be disconnected.
+#[derive(Debug)]
+pub struct Outbox {
+    commands: VecDeque<Command>,
+    capacity: usize,
+    overflow: Overflow,
+    overflowed: bool,
+    stats: OutboxStats
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_CAPACITY)
+    }
+}
+
+impl Outbox {
+    /// About four seconds of movement of eight players
+    pub const DEFAULT_CAPACITY: usize = 256;
+
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            commands: VecDeque::with_capacity(capacity),
+            capacity,
+            overflow: Overflow::default(),
+            overflowed: false,
+            stats: OutboxStats::default()
+        }
+    }
+
+    with!{ overflow: Overflow }
+    
+    pub fn push(&mut self, command: Command) {
+        if self.overflowed {
+            return;
+        }
+
+        if let Some(queued) = self.commands.iter_mut().find(|queued| Self::supersedes(&command, queued)) {
+            *queued = command;
+            self.stats.coalesced += 1;
+            return;
+        }
+
+        if self.commands.len() == self.capacity {
+            match self.overflow {
+                Overflow::Close => {
+                    self.overflowed = true;
+                    self.commands.clear();
+                    return;
+                },
+                Overflow::DropOldest => {
+                    self.commands.pop_front();
+                    self.stats.dropped += 1;
+                }
+            }
+        }
+
+        self.commands.push_back(command);
+        self.stats.peak = self.stats.peak.max(self.commands.len());
+    }
+
+    /// Every queued command, oldest first
+    pub fn drain(&mut self) -> VecDeque<Command> {
+        std::mem::take(&mut self.commands)
+    }
+
+    pub fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+
+    pub fn stats(&self) -> OutboxStats {
+        OutboxStats { depth: self.commands.len(), ..self.stats }
+    }
+
+    /// Whether `older` is useless once `newer` is sent
+    fn supersedes(newer: &Command, older: &Command) -> bool {
+        match (newer, older) {
+            (Command::Update(newer, _, changes), Command::Update(older, _, changed)) => newer == older && changes.covers(changed),
+            (Command::Acknowledge { .. }, Command::Acknowledge { .. }) => true,
+            // Snapshots carry everything since the last acknowledged one, so do acknowledgements
+            (Command::Snapshot(_), Command::Snapshot(_)) => true,
+            (Command::SnapshotAck(_), Command::SnapshotAck(_)) => true,
+            _ => false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use macroquad::prelude::*;
+
+    use crate::network::replication::EntityDelta;
+
+    #[test]
+    fn positions_are_coalesced_then_the_queue_overflows() {
+        let mut outbox = Outbox::new(3);
+
+        outbox.push(Command::Despawn(3));
+        outbox.push(Command::Update(1, 1, EntityDelta::moved(vec2(1.0, 0.0))));
+        outbox.push(Command::Update(2, 1, EntityDelta::moved(vec2(0.0, 0.0))));
+        outbox.push(Command::Update(1, 2, EntityDelta::moved(vec2(2.0, 0.0))));
+
+        let stats = outbox.stats();
+        assert_eq!((stats.depth, stats.peak, stats.coalesced), (3, 3, 1));
+        assert_eq!(outbox.drain(), [
+            Command::Despawn(3),
+            Command::Update(1, 2, EntityDelta::moved(vec2(2.0, 0.0))),
+            Command::Update(2, 1, EntityDelta::moved(vec2(0.0, 0.0)))
+        ]);
+
+        for id in 0..4 {
+            outbox.push(Command::Despawn(id));
+        }
+        assert!(outbox.overflowed());
+        assert!(outbox.drain().is_empty());
+    }
+    
+    #[test]
+    fn full_queues_can_drop_their_oldest_commands() {
+        let mut outbox = Outbox::new(2).with_overflow(Overflow::DropOldest);
+        
+        for id in 0..4 {
+            outbox.push(Command::Despawn(id));
+        }
+        
+        assert!(!outbox.overflowed());
+        assert_eq!(outbox.stats().dropped, 2);
+        assert_eq!(outbox.drain(), [Command::Despawn(2), Command::Despawn(3)]);
+    }
+}
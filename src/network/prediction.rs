@@ -0,0 +1,176 @@
+use macroquad::prelude::*;
+
+use std::collections::VecDeque;
+
+use crate::game::component::GameComponent;
+use crate::game::controller::Controller;
+use crate::game::keys::PlayerInput;
+use crate::game::map::Map;
+
+use super::sequence::Sequence;
+use super::server::GameServer;
+
+/// Client-side prediction of the local player.
+/// Inputs are simulated as soon as they are read, with the same fixed step as the server,
+/// then replayed on top of every authoritative state the server sends back.
+pub struct Prediction {
+    /// Inputs the server did not acknowledge yet, oldest first
+    pending: VecDeque<(u32, PlayerInput)>,
+    last_sequence: u32,
+    last_acknowledged: u32,
+    accumulator: f32,
+    /// Visual offset left by corrections, fading over time so that they do not snap
+    correction: Vec2
+}
+
+impl Default for Prediction {
+    fn default() -> Self {
+        Self {
+            pending: VecDeque::with_capacity(Self::CAPACITY),
+            last_sequence: 0,
+            last_acknowledged: 0,
+            accumulator: 0.0,
+            correction: Vec2::ZERO
+        }
+    }
+}
+
+impl Prediction {
+    /// Unacknowledged inputs kept for replay, about two seconds
+    const CAPACITY: usize = 128;
+    /// A long frame never runs more steps than this
+    const MAX_STEPS_PER_FRAME: usize = 8;
+    /// Part of a correction still visible after one second
+    const SMOOTHING: f32 = 0.0005;
+    /// Corrections larger than this are teleportations and are not smoothed
+    const SNAP_DISTANCE: f32 = 200.0;
+
+    /// Simulates `player` for the fixed steps fitting in `elapsed` seconds and returns
+    /// the numbered inputs of these steps, to be sent to the server
+    pub fn advance(&mut self, player: &mut GameComponent, map: &Map, input: PlayerInput, elapsed: f32) -> Vec<(u32, PlayerInput)> {
+        let dt = GameServer::TICK.as_secs_f32();
+        let mut steps = Vec::new();
+
+        self.accumulator = (self.accumulator + elapsed).min(dt * Self::MAX_STEPS_PER_FRAME as f32);
+        while self.accumulator >= dt {
+            self.accumulator -= dt;
+            self.last_sequence = self.last_sequence.wrapping_add(1);
+
+            Self::apply(player, map, input);
+
+            if self.pending.len() == Self::CAPACITY {
+                self.pending.pop_front();
+            }
+            self.pending.push_back((self.last_sequence, input));
+            steps.push((self.last_sequence, input));
+        }
+
+        self.correction *= Self::SMOOTHING.powf(elapsed);
+        steps
+    }
+
+    /// Rewinds `player` to the state the server reached after simulating input `sequence`,
+    /// then replays every input sent after it
+    pub fn reconcile(&mut self, player: &mut GameComponent, map: &Map, sequence: u32, position: Vec2, velocity: Vec2) {
+        // Acknowledgements may arrive out of order, only the newest one matters
+        if Sequence(self.last_acknowledged).is_newer_than(Sequence(sequence)) {
+            return;
+        }
+        self.last_acknowledged = sequence;
+
+        while let Some((oldest, _)) = self.pending.front() {
+            if Sequence(*oldest).is_newer_than(Sequence(sequence)) {
+                break;
+            }
+            self.pending.pop_front();
+        }
+
+        let displayed = self.displayed_position(player);
+
+        player.body.position = position;
+        player.body.velocity = velocity;
+        for (_, input) in self.pending.iter() {
+            Self::apply(player, map, *input);
+        }
+
+        self.correction = displayed - player.body.position;
+        if self.correction.length() > Self::SNAP_DISTANCE {
+            self.correction = Vec2::ZERO;
+        }
+    }
+
+    /// Where the player should be drawn
+    pub fn displayed_position(&self, player: &GameComponent) -> Vec2 {
+        player.body.position + self.correction
+    }
+
+    fn apply(player: &mut GameComponent, map: &Map, input: PlayerInput) {
+        if let Controller::Remote { input: current, .. } = &mut player.controller {
+            *current = input;
+        }
+        player.step(map, GameServer::TICK.as_secs_f32());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::game::object::GameObject;
+
+    fn player() -> GameComponent {
+        let mut player = GameComponent::from(GameObject::Player);
+        player.controller = player.controller.into_remote();
+        player
+    }
+
+    fn right() -> PlayerInput {
+        PlayerInput { slide: vec2(1.0, 0.0), ..Default::default() }
+    }
+
+    #[test]
+    fn agreeing_server_changes_nothing() {
+        let map = Map::default();
+        let dt = GameServer::TICK.as_secs_f32();
+
+        let mut predicted = player();
+        let mut server = player();
+        let mut prediction = Prediction::default();
+
+        let steps = prediction.advance(&mut predicted, &map, right(), dt * 3.5);
+        assert_eq!(steps.len(), 3);
+
+        // The server only simulated the first input so far
+        Prediction::apply(&mut server, &map, right());
+        let before = predicted.body.position;
+        prediction.reconcile(&mut predicted, &map, 1, server.body.position, server.body.velocity);
+
+        assert!(predicted.body.position.distance(before) < 1e-3);
+        assert!(prediction.correction.length() < 1e-3);
+        assert_eq!(prediction.pending.len(), 2);
+    }
+
+    #[test]
+    fn corrections_are_smoothed() {
+        let map = Map::default();
+        let dt = GameServer::TICK.as_secs_f32();
+
+        let mut predicted = player();
+        let mut prediction = Prediction::default();
+        prediction.advance(&mut predicted, &map, PlayerInput::default(), dt * 1.5);
+
+        let displayed = prediction.displayed_position(&predicted);
+        prediction.reconcile(&mut predicted, &map, 1, vec2(20.0, 0.0), Vec2::ZERO);
+
+        // The body jumps to the server position but is still drawn where it was
+        assert_eq!(predicted.body.position, vec2(20.0, 0.0));
+        assert_eq!(prediction.displayed_position(&predicted), displayed);
+
+        prediction.advance(&mut predicted, &map, PlayerInput::default(), 1.0);
+        assert!(prediction.displayed_position(&predicted).distance(vec2(20.0, 0.0)) < 0.1);
+
+        // Stale acknowledgements are ignored
+        prediction.reconcile(&mut predicted, &map, 0, Vec2::ZERO, Vec2::ZERO);
+        assert_eq!(predicted.body.position, vec2(20.0, 0.0));
+    }
+}
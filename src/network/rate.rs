@@ -0,0 +1,108 @@
+use std::time::Instant;
+
+use super::Command;
+
+/// Lets `capacity` events through at once, then `per_second` of them on average
+#[derive(Clone, Copy, Debug)]
+pub struct TokenBucket {
+    capacity: f32,
+    per_second: f32,
+    tokens: f32,
+    last_refill: Instant
+}
+
+impl TokenBucket {
+    /// Starts full
+    pub fn new(capacity: u32, per_second: f32) -> Self {
+        Self { capacity: capacity as f32, per_second, tokens: capacity as f32, last_refill: Instant::now() }
+    }
+
+    /// Whether one more event may go through, counting it if so
+    pub fn take(&mut self) -> bool {
+        self.take_at(Instant::now())
+    }
+
+    fn take_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f32();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Commands a connection may send, each kind having its own `TokenBucket`
+#[derive(Clone, Copy, Debug)]
+pub struct CommandLimits {
+    inputs: TokenBucket,
+    acknowledgements: TokenBucket,
+    chat: TokenBucket,
+    others: TokenBucket
+}
+
+impl Default for CommandLimits {
+    fn default() -> Self {
+        Self {
+            // Clients send one input and acknowledge one snapshot per simulation step, twice that leaves room for catching up
+            inputs: TokenBucket::new(120, 120.0),
+            acknowledgements: TokenBucket::new(120, 120.0),
+            chat: TokenBucket::new(10, 2.0),
+            others: TokenBucket::new(20, 5.0)
+        }
+    }
+}
+
+impl CommandLimits {
+    /// Counts `command`, returning the kind of commands it is if their limit is exceeded
+    pub fn check(&mut self, command: &Command) -> Result<(), &'static str> {
+        let (bucket, kind) = match command {
+            Command::Input(..) => (&mut self.inputs, "inputs"),
+            Command::SnapshotAck(_) | Command::Acknowledge { .. } => (&mut self.acknowledgements, "acknowledgements"),
+            Command::Chat { .. } => (&mut self.chat, "chat messages"),
+            _ => (&mut self.others, "commands")
+        };
+
+        if bucket.take() { Ok(()) } else { Err(kind) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn bursts_are_allowed_then_throttled() {
+        let mut bucket = TokenBucket::new(3, 2.0);
+        let start = bucket.last_refill;
+
+        assert!((0..3).all(|_| bucket.take_at(start)));
+        assert!(!bucket.take_at(start));
+
+        // Half a second gives one event back
+        assert!(bucket.take_at(start + Duration::from_millis(500)));
+        assert!(!bucket.take_at(start + Duration::from_millis(600)));
+
+        // Idle time does not let more than the capacity through
+        let later = start + Duration::from_secs(60);
+        assert!((0..3).all(|_| bucket.take_at(later)));
+        assert!(!bucket.take_at(later));
+    }
+
+    #[test]
+    fn command_kinds_are_limited_separately() {
+        let mut limits = CommandLimits::default();
+        let chat = Command::Chat { sender: String::new(), text: String::from("spam") };
+
+        assert!((0..10).all(|_| limits.check(&chat).is_ok()));
+        assert_eq!(limits.check(&chat), Err("chat messages"));
+        assert_eq!(limits.check(&Command::Input(1, Default::default())), Ok(()));
+        assert_eq!(limits.check(&Command::SnapshotAck(1)), Ok(()));
+    }
+}
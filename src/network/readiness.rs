@@ -0,0 +1,85 @@
+use std::time::Duration;
+
+/// A socket, as the operating system knows it
+pub type Descriptor = i32;
+
+/// A socket to wait for, until it can be read, or written if `write` is set
+#[derive(Clone, Copy, Debug)]
+pub struct Interest {
+    pub descriptor: Descriptor,
+    pub write: bool
+}
+
+#[cfg(unix)]
+#[repr(C)]
+struct PollDescriptor {
+    descriptor: Descriptor,
+    events: i16,
+    returned_events: i16
+}
+
+#[cfg(unix)]
+unsafe extern "C" {
+    fn poll(descriptors: *mut PollDescriptor, count: usize, timeout: i32) -> i32;
+}
+
+#[cfg(unix)]
+const POLLIN: i16 = 0x1;
+#[cfg(unix)]
+const POLLOUT: i16 = 0x4;
+
+/// Blocks until one of `interests` is ready, or `timeout` elapsed
+#[cfg(unix)]
+pub fn wait(interests: &[Interest], timeout: Duration) {
+    let mut descriptors = interests
+        .iter()
+        .map(|interest| PollDescriptor {
+            descriptor: interest.descriptor,
+            events: if interest.write { POLLIN | POLLOUT } else { POLLIN },
+            returned_events: 0
+        })
+        .collect::<Vec<_>>();
+
+    // Rounded up, so that a deadline less than a millisecond away is not spun on
+    let timeout = timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32;
+    unsafe { poll(descriptors.as_mut_ptr(), descriptors.len(), timeout) };
+}
+
+#[cfg(unix)]
+pub fn descriptor(socket: &impl std::os::fd::AsRawFd) -> Option<Descriptor> {
+    Some(socket.as_raw_fd())
+}
+
+/// Without `poll`, sockets are only checked once per `timeout`
+#[cfg(not(unix))]
+pub fn wait(_interests: &[Interest], timeout: Duration) {
+    std::thread::sleep(timeout);
+}
+
+#[cfg(not(unix))]
+pub fn descriptor<T>(_socket: &T) -> Option<Descriptor> {
+    None
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use std::net::UdpSocket;
+    use std::time::Instant;
+
+    #[test]
+    fn waits_until_readable() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let interests = [Interest { descriptor: descriptor(&socket).unwrap(), write: false }];
+
+        let start = Instant::now();
+        wait(&interests, Duration::from_millis(20));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        socket.send_to(b"ready", socket.local_addr().unwrap()).unwrap();
+        let start = Instant::now();
+        wait(&interests, Duration::from_secs(5));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
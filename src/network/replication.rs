@@ -0,0 +1,228 @@
+use macroquad::prelude::*;
+
+use std::collections::HashMap;
+
+use crate::game::body::Body;
+use crate::game::component::GameComponent;
+use crate::game::controller::{ Controller, ControllerKind };
+use crate::game::object::GameObject;
+
+use super::interpolation::SnapshotBuffer;
+use super::snapshot::World;
+
+/// What clients know of a `GameComponent` the server replicates
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entity {
+    pub object: GameObject,
+    pub controller: ControllerKind,
+    pub position: Vec2,
+    pub velocity: Vec2,
+    pub size: Vec2
+}
+
+impl From<&GameComponent> for Entity {
+    fn from(component: &GameComponent) -> Self {
+        Self {
+            object: component.object.clone(),
+            controller: component.controller.kind(),
+            position: component.body.position,
+            velocity: component.body.velocity,
+            size: component.body.size
+        }
+    }
+}
+
+impl Entity {
+    /// A component to draw the entity with, at `position`
+    pub fn to_component(&self, position: Vec2) -> GameComponent {
+        GameComponent {
+            body: Body { velocity: self.velocity, ..Body::default() }.with_position(position).with_size(self.size),
+            object: self.object.clone(),
+            // Replicas are moved by the server only
+            controller: Controller::BrainDead
+        }
+    }
+}
+
+/// The fields of an `Entity` that changed, sent as a `Command::Update`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EntityDelta {
+    pub object: Option<GameObject>,
+    pub controller: Option<ControllerKind>,
+    pub position: Option<Vec2>,
+    pub velocity: Option<Vec2>,
+    pub size: Option<Vec2>
+}
+
+impl EntityDelta {
+    /// A change of position only
+    pub fn moved(position: Vec2) -> Self {
+        Self { position: Some(position), ..Self::default() }
+    }
+
+    /// Fields of `new` that differ from `old`
+    pub fn between(old: &Entity, new: &Entity) -> Self {
+        fn changed<T: Clone + PartialEq>(old: &T, new: &T) -> Option<T> {
+            (old != new).then(|| new.clone())
+        }
+
+        Self {
+            object: changed(&old.object, &new.object),
+            controller: changed(&old.controller, &new.controller),
+            position: changed(&old.position, &new.position),
+            velocity: changed(&old.velocity, &new.velocity),
+            size: changed(&old.size, &new.size)
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Whether only the position and velocity changed, which change again on the next step anyway
+    pub fn is_motion(&self) -> bool {
+        self.object.is_none() && self.controller.is_none() && self.size.is_none()
+    }
+
+    /// Whether every field `older` changes is also changed by `self`, so that `older` is useless after it
+    pub fn covers(&self, older: &Self) -> bool {
+        (older.object.is_none() || self.object.is_some())
+        && (older.controller.is_none() || self.controller.is_some())
+        && (older.position.is_none() || self.position.is_some())
+        && (older.velocity.is_none() || self.velocity.is_some())
+        && (older.size.is_none() || self.size.is_some())
+    }
+
+    pub fn apply(&self, entity: &mut Entity) {
+        if let Some(object) = &self.object {
+            entity.object = object.clone();
+        }
+        if let Some(controller) = self.controller {
+            entity.controller = controller;
+        }
+        if let Some(position) = self.position {
+            entity.position = position;
+        }
+        if let Some(velocity) = self.velocity {
+            entity.velocity = velocity;
+        }
+        if let Some(size) = self.size {
+            entity.size = size;
+        }
+    }
+}
+
+/// An entity seen by a client, with the positions it was sent to interpolate between
+#[derive(Debug)]
+pub struct Replica {
+    pub entity: Entity,
+    pub snapshots: SnapshotBuffer
+}
+
+/// The entities a client was told about, except its own player
+#[derive(Debug, Default)]
+pub struct EntityStore {
+    entities: HashMap<usize, Replica>
+}
+
+impl EntityStore {
+    /// Adds an entity, or replaces the one that had the same id
+    pub fn spawn(&mut self, id: usize, entity: Entity) {
+        self.entities.insert(id, Replica { entity, snapshots: SnapshotBuffer::default() });
+    }
+
+    /// Applies the changes the server sent at `time`, in seconds. Unknown entities are ignored.
+    pub fn update(&mut self, id: usize, time: f64, delta: &EntityDelta) {
+        let Some(replica) = self.entities.get_mut(&id) else { return };
+        delta.apply(&mut replica.entity);
+        // A delta without a position, such as the one of an entity stopping, still says where it stands
+        replica.snapshots.push(time, replica.entity.position);
+    }
+
+    /// Spawns, updates and despawns entities to match `world`, as of server `time` in seconds
+    pub fn sync(&mut self, world: &World, time: f64) {
+        self.entities.retain(|id, _| world.contains_key(id));
+
+        for (id, state) in world.iter() {
+            let entity = state.to_entity();
+            match self.entities.get(id) {
+                Some(replica) => {
+                    let delta = EntityDelta::between(&replica.entity, &entity);
+                    self.update(*id, time, &delta);
+                },
+                None => {
+                    let position = entity.position;
+                    self.spawn(*id, entity);
+                    self.entities.get_mut(id).unwrap().snapshots.push(time, position);
+                }
+            }
+        }
+    }
+
+    pub fn despawn(&mut self, id: usize) {
+        self.entities.remove(&id);
+    }
+
+    pub fn clear(&mut self) {
+        self.entities.clear();
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Entity> {
+        self.entities.get(&id).map(|replica| &replica.entity)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Replica)> {
+        self.entities.iter().map(|(id, replica)| (*id, replica))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_changed_fields_are_sent() {
+        let mut component = GameComponent::from(GameObject::Monster).with_controller(Controller::Monster);
+        let before = Entity::from(&component);
+        assert_eq!(before.controller, ControllerKind::Monster);
+        assert!(EntityDelta::between(&before, &before).is_empty());
+
+        component.body.position = vec2(10.0, 0.0);
+        component.object = GameObject::CheckPoint { priority: 3 };
+        let after = Entity::from(&component);
+        let delta = EntityDelta::between(&before, &after);
+        assert_eq!(delta, EntityDelta {
+            object: Some(GameObject::CheckPoint { priority: 3 }),
+            position: Some(vec2(10.0, 0.0)),
+            ..EntityDelta::default()
+        });
+        assert!(!delta.is_motion() && delta.covers(&EntityDelta::moved(Vec2::ZERO)));
+        assert!(!EntityDelta::moved(Vec2::ZERO).covers(&delta));
+
+        let mut store = EntityStore::default();
+        store.spawn(4, before);
+        store.update(4, 1.0, &delta);
+        store.update(5, 1.0, &delta);
+        assert_eq!(store.get(4), Some(&after));
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn stopping_entities_are_not_extrapolated() {
+        let mut store = EntityStore::default();
+        store.spawn(1, Entity::from(&GameComponent::from(GameObject::Monster)));
+        store.update(1, 1.0, &EntityDelta::moved(vec2(0.0, 0.0)));
+        store.update(1, 2.0, &EntityDelta::moved(vec2(10.0, 0.0)));
+        store.update(1, 3.0, &EntityDelta { velocity: Some(Vec2::ZERO), ..EntityDelta::default() });
+
+        assert_eq!(store.entities[&1].snapshots.sample(5.0, 0.25), Some(vec2(10.0, 0.0)));
+    }
+}
@@ -0,0 +1,54 @@
+use super::FormatError;
+use super::codec::{ Reader, Shareable };
+
+/// 32-bit sequence number that wraps around.
+/// Two sequences are compared with serial number arithmetic (RFC 1982): `a` is newer
+/// than `b` when it is less than half the sequence space ahead of it.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Sequence(pub u32);
+
+impl Sequence {
+    const HALF: u32 = 1 << 31;
+    
+    pub fn next(self) -> Self {
+        Self(self.0.wrapping_add(1))
+    }
+    
+    pub fn is_newer_than(self, other: Self) -> bool {
+        self != other && self.0.wrapping_sub(other.0) < Self::HALF
+    }
+    
+    /// How many increments lead from `older` to `self`
+    pub fn distance_from(self, older: Self) -> u32 {
+        self.0.wrapping_sub(older.0)
+    }
+}
+
+impl Shareable for Sequence {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.0.encode(bytes);
+    }
+    
+    fn decode(reader: &mut Reader) -> Result<Self, FormatError> {
+        Ok(Self(reader.read()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn comparison_wraps_around() {
+        let last = Sequence(u32::MAX);
+        
+        assert_eq!(last.next(), Sequence(0));
+        assert!(last.next().is_newer_than(last));
+        assert!(!last.is_newer_than(last.next()));
+        assert!(!last.is_newer_than(last));
+        assert!(Sequence(5).is_newer_than(Sequence(u32::MAX - 5)));
+        assert_eq!(Sequence(5).distance_from(Sequence(u32::MAX - 5)), 11);
+        // More than half the space ahead is considered behind
+        assert!(!Sequence(Sequence::HALF + 1).is_newer_than(Sequence(0)));
+    }
+}
@@ -1,197 +1,1731 @@
 use macroquad::prelude::*;
 
-use std::collections::VecDeque;
-use std::net::{ TcpListener, TcpStream };
+use std::collections::{ HashMap, VecDeque };
+use std::net::{ IpAddr, SocketAddr, TcpListener, UdpSocket };
 use std::io::Error;
-use std::sync::{Arc, Mutex};
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, Ordering };
 use std::thread::{self, JoinHandle};
-use std::time::Duration;
+use std::time::{ Duration, Instant };
 
+use auto_with::with;
+use desi_ui::{ Layout, Widget, WidgetData };
+
+use crate::game::component::GameComponent;
+use crate::game::keys::PlayerInput;
+use crate::game::map::Map;
+use crate::game::object::GameObject;
+use crate::game::controller::Controller;
 use crate::utils::{ Controlable, Drawable, Dynamic };
 use crate::utils::{ base_format, Random, Time };
 
-use super::{Command, GameAgent, Protocol, ProtocolError};
+use super::area::Area;
+use super::bans::Bans;
+use super::connection::Connection;
+use super::console::Order;
+use super::dashboard::{ ClientRow, Dashboard, Marker };
+use super::discovery::{ Announcement, Beacon };
+use super::datagram::DatagramProtocol;
+use super::outbox::Outbox;
+use super::rate::{ CommandLimits, TokenBucket };
+use super::readiness::{ self, Interest };
+use super::replication::{ Entity, EntityDelta };
+use super::snapshot::{ EntityState, SnapshotHistory, World };
+use super::transport::{ Listener, MemoryConnector, MemoryListener, Transport };
+use super::{ Command, DisconnectionReason, GameAgent, Protocol, ProtocolError, RejectionReason };
+
+/// A player's character and the inputs it sent that were not simulated yet.
+/// It outlives its connection for `GameServer::SESSION_GRACE`, so that the client can resume it.
+struct Player {
+    component: GameComponent,
+    inputs: VecDeque<(u32, PlayerInput)>,
+    acknowledged: u32,
+    name: String,
+    session: u64,
+    disconnected_since: Option<Instant>,
+    /// Limits how often it may talk
+    chat: TokenBucket,
+    /// Whether its chat may run console commands, only the host playing in-process can
+    admin: bool
+}
+
+/// A connection that did not send its `Command::Hello` yet
+struct Handshake {
+    stream: Box<dyn Transport>,
+    /// Address of the listener the connection came from, if it is reachable from the network
+    local: Option<SocketAddr>,
+    protocol: Protocol,
+    since: Instant
+}
+
+/// How the server serves its clients' connections
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Scheduling {
+    /// Each client gets a thread polling its connection every millisecond
+    #[default]
+    ThreadPerClient,
+    /// Every connection is served by `GameServer::update`, `GameServer::wait` sleeping until one of them is ready
+    EventLoop
+}
+
+enum Runner {
+    /// Returns whether the client left on purpose
+    Thread(JoinHandle<bool>),
+    /// Stepped by the server, with whether the client left on purpose once its connection ended
+    Polled(Box<Client>, Option<bool>)
+}
 
-struct Message {
-    body: Command,
-    source: usize,
-    read_by: Vec<usize>
+/// What the host view shows about a client's connection, updated by whoever serves it
+#[derive(Clone, Copy, Debug, Default)]
+struct Traffic {
+    rtt: Option<Duration>,
+    datagrams: bool,
+    sent: u64,
+    received: u64
+}
+
+struct ClientHandle {
+    runner: Runner,
+    id: usize,
+    address: String,
+    ip: Option<IpAddr>,
+    traffic: Arc<Mutex<Traffic>>,
+    outbox: Arc<Mutex<Outbox>>,
+    /// Asks the client thread to close its connection
+    kicked: Arc<AtomicBool>,
+    /// Last snapshot the client applied, and those it may have
+    acknowledged: Arc<Mutex<Option<u32>>>,
+    snapshots: SnapshotHistory
 }
 
 struct Client {
-    stream: TcpStream,
+    connection: Connection,
+    /// Socket offered to the client, until it sends its `Command::Hello` to it
+    offer: Option<UdpSocket>,
     
     id: usize,
-    protocol: Protocol,
+    session: u64,
+    inputs: Arc<Mutex<VecDeque<(usize, u32, PlayerInput)>>>,
+    chat: Arc<Mutex<VecDeque<(usize, String)>>>,
+    outbox: Arc<Mutex<Outbox>>,
+    kicked: Arc<AtomicBool>,
+    traffic: Arc<Mutex<Traffic>>,
+    acknowledged: Arc<Mutex<Option<u32>>>,
+    limits: CommandLimits,
+    /// Applied to the datagram protocol too, once the client switches to it
+    idle_timeout: Duration,
+    last_report: Instant,
     
-    disconnected: bool
+    disconnected: bool,
+    left: bool
 }
 
 pub struct GameServer {    
+    /// How the server is presented on the local network
+    name: String,
     map_seed: usize,
+    max_players: usize,
+    max_connections_per_ip: usize,
+    idle_timeout: Duration,
+    scheduling: Scheduling,
     
-    clients: Vec<(JoinHandle<()>, usize)>,
-    listener: TcpListener,
+    map: Map,
+    players: HashMap<usize, Player>,
+    /// Replicated components that are not players, such as monsters
+    entities: HashMap<usize, GameComponent>,
+    /// Number of the last snapshot sent
+    tick: u32,
+    started: Instant,
+    last_tick: Instant,
     
-    broadcast_queue: Arc<Mutex<VecDeque<Message>>>,
+    clients: Vec<ClientHandle>,
+    handshakes: Vec<Handshake>,
+    listeners: Vec<Box<dyn Listener>>,
+    beacon: Option<Beacon>,
+    
+    inputs: Arc<Mutex<VecDeque<(usize, u32, PlayerInput)>>>,
+    /// Chat messages received, with their sender's id
+    chat: Arc<Mutex<VecDeque<(usize, String)>>>,
+    
+    bans: Bans,
+    /// Set once the console shut the server down
+    stopped: bool,
+    /// Console of the host view
+    prompt: Widget,
+    dashboard: Dashboard
 }
 
+/// Lines logged by `GameServer::log`, oldest first, for the host view
+static LOG: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
 impl GameAgent for GameServer {}
 impl Controlable for GameServer {
     fn handle_events(&mut self) -> bool {
-        // TODO: Command prompt maybe ?
-        false
+        self.prompt.update_absolutes(Layout::new(
+            vec2(screen_width() / 2.0, screen_height() / 2.0),
+            vec2(screen_width(), screen_height())
+        ));
+        
+        // Clicking elsewhere also activates the prompt, only Enter submits it
+        let submitted = is_key_pressed(KeyCode::Enter);
+        for activation in self.prompt.get_activations() {
+            let line = activation.message.unwrap_or_default();
+            if !submitted || line == Self::PROMPT_PLACEHOLDER || line.trim().is_empty() {
+                continue;
+            }
+            
+            Self::log(&format!("> {line}"));
+            for answer in self.execute(&line).lines() {
+                Self::log(answer);
+            }
+            self.prompt = Self::prompt();
+        }
+        
+        let log_length = LOG.lock().unwrap().len();
+        self.dashboard.handle_events(&self.map, log_length);
+        
+        true
     }
 }
 
 impl Drawable for GameServer {
     fn draw(&self) {
-        // TODO
+        let mut players = self.players.iter().collect::<Vec<_>>();
+        players.sort_by_key(|(id, _)| **id);
+        
+        let markers = players
+            .iter()
+            .map(|(id, player)| Marker {
+                id: **id,
+                position: player.component.body.position,
+                size: player.component.body.size,
+                connected: player.disconnected_since.is_none()
+            })
+            .collect::<Vec<_>>();
+        
+        let clients = players
+            .iter()
+            .map(|(id, player)| {
+                let handle = self.clients.iter().find(|client| client.id == **id);
+                let traffic = handle.map(|client| *client.traffic.lock().unwrap()).unwrap_or_default();
+                ClientRow {
+                    id: **id,
+                    name: player.name.clone(),
+                    address: handle.map(|client| client.address.clone()),
+                    rtt: traffic.rtt,
+                    datagrams: traffic.datagrams,
+                    sent: traffic.sent,
+                    received: traffic.received
+                }
+            })
+            .collect::<Vec<_>>();
+        
+        let log = LOG.lock().unwrap().iter().cloned().collect::<Vec<_>>();
+        self.dashboard.draw(&self.map, &markers, &clients, self.max_players, &log);
+        self.prompt.draw();
     }
 }
 
 impl Dynamic for GameServer {
     fn update(&mut self) {
+        self.announce();
         self.accept_connections();
-        
-        let mut queue = self.broadcast_queue.lock().unwrap();
-        
-        // Removing messages read by every clients
-        if !queue.is_empty() {
-            for i in queue.len()-1..=0 {
-                if queue[i].read_by.len() >= self.clients.len() {
-                    queue.remove(i);
-                }
-            }
+        self.process_handshakes();
+        self.handle_departures();
+        self.expire_sessions();
+        self.simulate();
+        self.process_chat();
+        self.step_clients();
+    }
+}
+
+impl ClientHandle {
+    fn is_finished(&self) -> bool {
+        match &self.runner {
+            Runner::Thread(thread) => thread.is_finished(),
+            Runner::Polled(_, outcome) => outcome.is_some()
+        }
+    }
+    
+    /// Whether the client left on purpose, once it is finished
+    fn left(self) -> bool {
+        match self.runner {
+            Runner::Thread(thread) => thread.join().unwrap_or(false),
+            Runner::Polled(_, outcome) => outcome.unwrap_or(false)
+        }
+    }
+}
+
+impl Drop for GameServer {
+    fn drop(&mut self) {
+        if !self.clients.is_empty() {
+            self.shutdown();
         }
     }
 }
 
 impl Client {
     
-    fn tick(&mut self, message_queue: &mut VecDeque<Message>) {
-        self.receive(message_queue);
-        self.send(message_queue);
+    /// Serves the connection once, returning whether the client left on purpose once it ended
+    fn step(&mut self) -> Option<bool> {
+        let (sent, received) = self.connection.transferred();
+        *self.traffic.lock().unwrap() = Traffic {
+            rtt: self.connection.stats().rtt,
+            datagrams: self.connection.uses_datagrams(),
+            sent,
+            received
+        };
+        
+        if self.last_report.elapsed() >= GameServer::REPORT_INTERVAL {
+            self.last_report = Instant::now();
+            GameServer::log(&format!("Client {} ({})", self.id, self.report()));
+        }
+        
+        if self.kicked.load(Ordering::Relaxed) {
+            GameServer::log(&format!("Connection of client {} replaced ({})", self.id, self.report()));
+            return Some(false);
+        }
+        
+        self.tick();
+        if self.disconnected {
+            GameServer::log(&format!("Client {} disconnected ({})", self.id, self.report()));
+            return Some(self.left);
+        }
+        
+        None
+    }
+    
+    fn tick(&mut self) {
+        self.accept_datagrams();
+        self.receive();
+        self.send();
+        
+        if !self.disconnected && self.connection.heartbeat().is_err() {
+            GameServer::log(&format!("Client {} stopped answering", self.id));
+            self.disconnected = true;
+        }
+    }
+    
+    /// Connection statistics, for the logs
+    fn report(&self) -> String {
+        let stats = self.connection.stats();
+        let queue = self.outbox.lock().unwrap().stats();
+        format!(
+            "{}, RTT: {}, jitter: {} ms, frames sent: {}, received: {}, lost: {}, duplicated: {}, resent: {}, unacknowledged: {}, queued: {} (peak {}, coalesced {})",
+            if self.connection.uses_datagrams() { "UDP" } else { "TCP" },
+            stats.rtt.map_or(String::from("unknown"), |rtt| format!("{} ms", rtt.as_millis())),
+            stats.jitter.as_millis(),
+            stats.sent,
+            stats.received,
+            stats.lost,
+            stats.duplicated,
+            stats.resent,
+            self.connection.unacknowledged(),
+            queue.depth,
+            queue.peak,
+            queue.coalesced
+        )
+    }
+    
+    /// Sockets to wait on for this client to make progress
+    fn interests(&self) -> Vec<Interest> {
+        let offer = self.offer
+            .as_ref()
+            .and_then(readiness::descriptor)
+            .map(|descriptor| Interest { descriptor, write: false });
+        
+        self.connection.interests().into_iter().chain(offer).collect()
+    }
+    
+    /// Switches to the offered socket once the client sends its session token to it
+    fn accept_datagrams(&mut self) {
+        let Some(socket) = &self.offer else { return };
+        
+        let mut buffer = [0u8; DatagramProtocol::MAX_SIZE];
+        while let Ok((size, address)) = socket.recv_from(&mut buffer) {
+            let mut protocol = DatagramProtocol::new().with_idle_timeout(self.idle_timeout);
+            if protocol.feed(&buffer[..size]).is_err() {
+                continue;
+            }
+            
+            if let Some(Command::Hello { session: Some(session), .. }) = protocol.delivered()
+            && session == self.session
+            && socket.connect(address).is_ok() {
+                GameServer::log(&format!("Client {} switched to UDP from {address}", self.id));
+                self.connection.attach(self.offer.take().unwrap(), protocol, true);
+                let _ = self.connection.send(Command::Welcome(self.id, self.session));
+                return;
+            }
+        }
     }
     
-    fn receive(&mut self, message_queue: &mut VecDeque<Message>) {
-        match &mut self.protocol.reception(&mut self.stream) {
-            Ok(command) => {
-                match command {
-                    Command::Spawn(id) | Command::Reposition(id, _) => { *id = self.id; },
+    /// Handles every command received since the last call
+    fn receive(&mut self) {
+        while !self.disconnected {
+            match &mut self.connection.reception() {
+                Ok(command) => match command {
+                    _ if let Err(kind) = self.limits.check(command) => self.reject_flood(&format!("too many {kind}")),
+                    Command::Input(sequence, input) => self.inputs.lock().unwrap().push_back((self.id, *sequence, *input)),
+                    Command::Chat { text, .. } => self.chat.lock().unwrap().push_back((self.id, std::mem::take(text))),
+                    Command::SnapshotAck(tick) => {
+                        let mut acknowledged = self.acknowledged.lock().unwrap();
+                        *acknowledged = (*acknowledged).max(Some(*tick));
+                    },
+                    // A client despawning itself is leaving the game
+                    Command::Despawn(_) => {
+                        self.disconnected = true;
+                        self.left = true;
+                    },
+                    Command::Disconnect(reason) => {
+                        GameServer::log(&format!("Client {} closed its connection: {reason}", self.id));
+                        self.disconnected = true;
+                        self.left = true;
+                    },
+                    Command::Unknown | Command::IllFormated(_) => self.reject_malformed(&format!("a malformed command ({command:?})")),
+                    // The server owns the world, anything else is ignored
                     _ => {}
-                }
-                message_queue.push_back(Message { body: *command, source: self.id, read_by: vec![self.id] })
-            },
-            Err(e) => match e {
-                ProtocolError::Disconnection => self.disconnected = true,
-                ProtocolError::WrongSequence => {
-                    // TODO
-                },
-                ProtocolError::OutdatedPackage => {
-                    // TODO
-                },
-                ProtocolError::IllFormatedSequenceNumber => {
-                    // TODO
                 },
+                Err(e) => {
+                    match e {
+                        ProtocolError::Disconnection => self.disconnected = true,
+                        ProtocolError::Pending => {},
+                        ProtocolError::WrongSequence => {
+                            GameServer::log(&format!("Could not read from client {}, will try again", self.id));
+                        },
+                        // Datagrams may be duplicated on their way, they are only counted in the statistics
+                        ProtocolError::OutdatedPackage => {},
+                        ProtocolError::IllFormatedSequenceNumber => {
+                            self.reject_malformed("a corrupted frame, skipped to the next one");
+                        },
+                        ProtocolError::FrameTooLarge => self.reject_flood("a frame too large"),
+                        ProtocolError::TooManyPings => self.reject_flood("too many pings"),
+                    }
+                    return;
+                }
             }
         }
     }
     
-    fn send(&mut self, message_queue: &mut VecDeque<Message>) {
-        for message in message_queue.iter_mut() {
-            if message.source != self.id && !message.read_by.contains(&self.id) {
-                message.read_by.push(self.id);
-                match self.protocol.send(&mut self.stream, message.body) {
-                    Ok(_) => {},
-                    Err(e) => GameServer::log(&format!("Error while sending message: {e:?}")),
-                }
+    /// Logs what the client sent, and disconnects it once it sent too many things that could not be understood
+    fn reject_malformed(&mut self, what: &str) {
+        let malformed = self.connection.malformed();
+        GameServer::log(&format!("Client {} sent {what} ({malformed}/{})", self.id, Connection::MAX_MALFORMED));
+        
+        if malformed >= Connection::MAX_MALFORMED {
+            GameServer::log(&format!("Disconnecting client {}: {}", self.id, DisconnectionReason::Malformed));
+            self.connection.close(DisconnectionReason::Malformed);
+            self.disconnected = true;
+            self.left = true;
+        }
+    }
+    
+    /// Disconnects the client for sending more than it is allowed to
+    fn reject_flood(&mut self, what: &str) {
+        GameServer::log(&format!("Client {} sent {what}, disconnecting it: {}", self.id, DisconnectionReason::Flooding));
+        self.connection.close(DisconnectionReason::Flooding);
+        self.disconnected = true;
+        self.left = true;
+    }
+    
+    fn send(&mut self) {
+        // The slot is kept, the client will get a fresh state if it reconnects
+        if self.outbox.lock().unwrap().overflowed() {
+            GameServer::log(&format!("Client {} could not keep up with its outbound queue", self.id));
+            self.disconnected = true;
+            return;
+        }
+        
+        // Commands wait in the outbox, where positions are coalesced, until the stream took the previous ones
+        let _ = self.connection.flush();
+        if self.connection.backlogged() {
+            return;
+        }
+        
+        let commands = self.outbox.lock().unwrap().drain();
+        for command in commands {
+            // Nothing is sent after telling the client why it is disconnected
+            if let Command::Disconnect(reason) = command {
+                self.connection.close(reason);
+                self.disconnected = true;
+                self.left = true;
+                return;
+            }
+            
+            if let Err(e) = self.connection.send(command) {
+                GameServer::log(&format!("Error while sending message: {e:?}"));
+            }
+        }
+    }
+}
+
+impl Player {
+    /// Simulates the next buffered input, or the last one again if the client is late
+    fn step(&mut self, map: &Map, dt: f32) {
+        if let Some((sequence, input)) = self.inputs.pop_front() {
+            if let Controller::Remote { input: current, .. } = &mut self.component.controller {
+                *current = input;
             }
+            self.acknowledged = sequence;
         }
+        
+        self.component.step(map, dt);
     }
 }
 
 impl GameServer {
     
-    pub const MAP_WIDTH: usize = 50;
-    pub const MAP_HEIGHT: usize = 50;
+    pub const DEFAULT_MAP_WIDTH: usize = 50;
+    pub const DEFAULT_MAP_HEIGHT: usize = 50;
+    pub const DEFAULT_MAX_PLAYERS: usize = 8;
+    /// Connections, pending handshakes included, one address may have open at once
+    pub const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 4;
+    /// Longest frame a client may send, commands from clients being small
+    const MAX_CLIENT_FRAME_LENGTH: usize = 4096;
+    pub const DEFAULT_NAME: &str = "Unnamed server";
+    const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+    /// How long `shutdown` waits for the clients to be told
+    const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+    /// How long the slot of a player whose connection dropped is kept
+    pub const SESSION_GRACE: Duration = Duration::from_secs(30);
+    /// How often each client's connection statistics are logged
+    const REPORT_INTERVAL: Duration = Duration::from_secs(30);
+    
+    /// Duration of one simulation step
+    pub const TICK: Duration = Duration::from_micros(16_667);
+    /// Beyond this many late steps, the simulation gives up catching up
+    const MAX_STEPS_PER_UPDATE: usize = 5;
+    /// Inputs buffered per player, older ones are dropped when a client sends too many
+    const MAX_BUFFERED_INPUTS: usize = 32;
+    /// How often `wait` checks the connections it cannot wait on, such as in-process ones
+    const POLL_INTERVAL: Duration = Duration::from_millis(1);
+    
+    /// Characters a chat message may have
+    pub const MAX_CHAT_LENGTH: usize = 200;
+    /// Chat messages a player may send at once, and per second after that
+    const CHAT_BURST: u32 = 5;
+    const CHAT_RATE: f32 = 0.5;
+    
+    /// Where the host view keeps its bans
+    pub const BAN_FILE: &str = "bans.txt";
+    const PROMPT_PLACEHOLDER: &str = "Type a command, help for the list";
+    const PROMPT_LAYOUT: Layout = Layout { center: vec2(0.0, 0.45), scale: vec2(0.9, 0.04) };
+    /// Lines `log` keeps for the host view
+    const LOG_LENGTH: usize = 1000;
     
     pub fn new(connection_string: &str) -> Result<Self, Error> {
         let listener = TcpListener::bind(connection_string)?;
         listener.set_nonblocking(true)?;
+        Self::log(&format!("Listening on {}", listener.local_addr()?));
         
-        Ok(Self {
-            map_seed: Random::any(),
+        let mut server = Self::offline();
+        server.listeners.push(Box::new(listener));
+        Ok(server)
+    }
+    
+    /// A server only reachable from this process, through `connector`
+    pub fn offline() -> Self {
+        let map_seed = Random::any();
+        let map = Map::generate(Self::DEFAULT_MAP_WIDTH, Self::DEFAULT_MAP_HEIGHT, map_seed);
+        // Map generation reseeds the generator, ids must not be derived from the public seed
+        Random::seed();
+        
+        Self {
+            name: String::from(Self::DEFAULT_NAME),
+            map_seed,
+            max_players: Self::DEFAULT_MAX_PLAYERS,
+            max_connections_per_ip: Self::DEFAULT_MAX_CONNECTIONS_PER_IP,
+            idle_timeout: Protocol::DEFAULT_IDLE_TIMEOUT,
+            scheduling: Scheduling::default(),
+            map,
+            players: HashMap::new(),
+            entities: HashMap::new(),
+            tick: 0,
+            started: Instant::now(),
+            last_tick: Instant::now(),
             clients: Vec::default(),
-            listener,
-            broadcast_queue: Arc::new(Mutex::new(VecDeque::default())),
+            handshakes: Vec::default(),
+            listeners: Vec::default(),
+            beacon: None,
+            inputs: Arc::new(Mutex::new(VecDeque::default())),
+            chat: Arc::new(Mutex::new(VecDeque::default())),
+            bans: Bans::default(),
+            stopped: false,
+            prompt: Self::prompt(),
+            dashboard: Dashboard::default()
+        }
+    }
+    
+    with!{ scheduling: Scheduling }
+    with!{ max_players: usize }
+    with!{ max_connections_per_ip: usize }
+    with!{ idle_timeout: Duration }
+    with!{ bans: Bans }
+    with!{ name: String }
+    
+    /// Announces the server on the local network, see `Scanner`
+    pub fn with_beacon(mut self, beacon: Beacon) -> Self {
+        self.beacon = Some(beacon);
+        self
+    }
+    
+    pub fn with_map(mut self, seed: usize, width: usize, height: usize) -> Self {
+        self.change_map(seed, width, height);
+        self
+    }
+    
+    fn prompt() -> Widget {
+        Widget::new(WidgetData::TextInput {
+            placeholder: Self::PROMPT_PLACEHOLDER.to_string(),
+            input: String::new(),
+            selected: true
         })
+        .with_id("console")
+        .with_primary(BLACK)
+        .with_secondary(WHITE)
+        .with_relative(Self::PROMPT_LAYOUT)
+    }
+    
+    /// Whether the console shut the server down, it does not accept connections anymore
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+    
+    /// Opens an in-process entrance to the server, for clients running in the same program
+    pub fn connector(&mut self) -> MemoryConnector {
+        let (listener, connector) = MemoryListener::new();
+        self.listeners.push(Box::new(listener));
+        connector
+    }
+    
+    /// Address the server listens on, if it is reachable from the network
+    pub fn address(&self) -> Option<SocketAddr> {
+        self.listeners.iter().find_map(|listener| listener.local_address())
+    }
+    
+    /// Tells the local network about the server, if it has a beacon and can be reached from the network
+    fn announce(&mut self) {
+        if !self.beacon.as_ref().is_some_and(Beacon::is_due) {
+            return;
+        }
+        let Some(address) = self.address() else { return };
+        
+        let announcement = Announcement {
+            name: self.name.clone(),
+            players: self.players.len(),
+            max_players: self.max_players,
+            seed: self.map_seed,
+            version: Protocol::VERSION,
+            port: address.port()
+        };
+        if let Some(beacon) = &mut self.beacon {
+            beacon.announce(&announcement);
+        }
     }
     
     pub fn accept_connections(&mut self) {
-        if let Ok(mut stream) = self.listener.accept() {
-            stream.0.set_nonblocking(true).unwrap();
-            
-            // Sending initial messages (map seed and other players)
-            let mut new_protocol = Protocol::new();
-            new_protocol.send(&mut stream.0, Command::ChangeMap(self.map_seed)).unwrap();
-            for (_, id) in self.clients.iter() {
-                new_protocol.send(&mut stream.0, Command::Spawn(*id)).unwrap();
-            }
-
-            self.clients.push({
-                let new_id = Random::any();
-                let client = Client {
-                    stream: stream.0,
-                    id: new_id,
-                    protocol: new_protocol,
-                    disconnected: false
-                };
-                let queue = Arc::clone(&self.broadcast_queue);
+        for listener in self.listeners.iter_mut() {
+            if let Some(mut stream) = listener.accept() {
+                Self::log(&format!("Incoming connection from {}", stream.peer()));
                 
-                {
-                    // Broadcasting spawn command to other players
-                    queue
-                        .lock()
-                        .unwrap()
-                        .push_back(Message {
-                            body: Command::Spawn(new_id),
-                            source: new_id,
-                            read_by: Vec::default()
-                        });
+                // In-process connections have no address, they are never refused
+                let ip = stream.ip();
+                let open = self.handshakes
+                    .iter()
+                    .map(|handshake| handshake.stream.ip())
+                    .chain(self.clients.iter().map(|client| client.ip))
+                    .filter(|other| ip.is_some() && *other == ip)
+                    .count();
+                if open >= self.max_connections_per_ip {
+                    let reason = RejectionReason::TooManyConnections;
+                    Self::log(&format!("Refused a connection from {}: {reason}", stream.peer()));
+                    let _ = Protocol::new().send(&mut stream, Command::Rejected(reason));
+                    continue;
                 }
                 
-                (thread::spawn(move || Self::tick_client(client, queue)), new_id)
-            });
+                self.handshakes.push(Handshake {
+                    stream,
+                    local: listener.local_address(),
+                    protocol: Protocol::new()
+                        .with_idle_timeout(self.idle_timeout)
+                        .with_max_frame_length(Self::MAX_CLIENT_FRAME_LENGTH),
+                    since: Instant::now()
+                });
+            }
+        }
+    }
+    
+    /// Answers every pending `Command::Hello` and drops handshakes that took too long
+    fn process_handshakes(&mut self) {
+        let mut still_pending = Vec::new();
+        
+        for mut handshake in std::mem::take(&mut self.handshakes) {
+            match handshake.protocol.reception(&mut handshake.stream) {
+                Ok(Command::Hello { version, name, build, session }) => match self.admit(version, &name, session) {
+                    Err(reason) => {
+                        Self::log(&format!("Refused {name} ({build}): {reason}"));
+                        let _ = handshake.protocol.send(&mut handshake.stream, Command::Rejected(reason));
+                    },
+                    Ok(Some(id)) => {
+                        Self::log(&format!("{name} resumed its session as client {id} ({build})"));
+                        self.resume_client(handshake, id);
+                    },
+                    Ok(None) => {
+                        Self::log(&format!("{name} joined ({build})"));
+                        self.add_client(handshake, name);
+                    }
+                },
+                Err(ProtocolError::Pending) if handshake.since.elapsed() < Self::HANDSHAKE_TIMEOUT => {
+                    still_pending.push(handshake);
+                },
+                _ => Self::log("Dropped a connection that did not complete its handshake")
+            }
+        }
+        
+        self.handshakes = still_pending;
+    }
+    
+    /// Returns the id of the player to resume, if `session` matches one
+    fn admit(&self, version: u16, name: &str, session: Option<u64>) -> Result<Option<usize>, RejectionReason> {
+        let resumed = session.and_then(|session| {
+            self.players
+                .iter()
+                .find(|(_, player)| player.session == session)
+                .map(|(id, _)| *id)
+        });
+        
+        // Disconnected players keep their slot and their name until their session expires
+        if version != Protocol::VERSION {
+            Err(RejectionReason::VersionMismatch { server: Protocol::VERSION })
+        } else if self.bans.contains(name) {
+            Err(RejectionReason::Banned)
+        } else if resumed.is_some() {
+            Ok(resumed)
+        } else if self.players.len() >= self.max_players {
+            Err(RejectionReason::ServerFull)
+        } else if self.players.values().any(|player| player.name == name) {
+            Err(RejectionReason::NameTaken)
+        } else {
+            Ok(None)
+        }
+    }
+    
+    fn add_client(&mut self, handshake: Handshake, name: String) {
+        let Handshake { mut stream, mut protocol, local, .. } = handshake;
+        
+        let new_id = self.new_id();
+        let session = Random::token();
+        
+        let mut player = GameComponent::from(GameObject::Player);
+        player.body.position = Map::spawn_point();
+        player.controller = player.controller.into_remote();
+        
+        // Sending initial messages (assigned id, map seed and our position), the world comes with the first snapshot
+        let _ = protocol.send(&mut stream, Command::Welcome(new_id, session));
+        self.send_world(&mut stream, &mut protocol);
+        let _ = protocol.send(&mut stream, Command::Update(new_id, self.time(), EntityDelta::moved(player.body.position)));
+        
+        self.players.insert(new_id, Player {
+            component: player,
+            inputs: VecDeque::new(),
+            acknowledged: 0,
+            name,
+            session,
+            disconnected_since: None,
+            chat: TokenBucket::new(Self::CHAT_BURST, Self::CHAT_RATE),
+            // Connections that did not come through a network listener come from this process
+            admin: local.is_none()
+        });
+        self.spawn_client(stream, protocol, local, new_id, session);
+    }
+    
+    /// Hands a player kept since its disconnection to a new connection
+    fn resume_client(&mut self, handshake: Handshake, id: usize) {
+        let Handshake { mut stream, mut protocol, local, .. } = handshake;
+        
+        // The previous connection may not have timed out yet
+        if let Some(index) = self.clients.iter().position(|client| client.id == id) {
+            self.clients.swap_remove(index).kicked.store(true, Ordering::Relaxed);
         }
+        
+        let Some(player) = self.players.get_mut(&id) else { return };
+        player.disconnected_since = None;
+        let session = player.session;
+        let acknowledge = Command::Acknowledge {
+            sequence: player.acknowledged,
+            position: player.component.body.position,
+            velocity: player.component.body.velocity
+        };
+        
+        // The client keeps its input numbering, acknowledging the last simulated one restores its state
+        let _ = protocol.send(&mut stream, Command::Welcome(id, session));
+        self.send_world(&mut stream, &mut protocol);
+        let _ = protocol.send(&mut stream, acknowledge);
+        
+        self.spawn_client(stream, protocol, local, id, session);
+    }
+    
+    /// Sends the map seed to a newly connected client
+    fn send_world(&self, stream: &mut Box<dyn Transport>, protocol: &mut Protocol) {
+        let _ = protocol.send(stream, Command::ChangeMap { seed: self.map_seed, width: self.map.width, height: self.map.height });
     }
     
-    fn tick_client(mut client: Client, broadcast_queue: Arc<Mutex<VecDeque<Message>>>) {
+    /// Every replicated component, players included
+    fn components(&self) -> impl Iterator<Item = (usize, &GameComponent)> {
+        self.players
+            .iter()
+            .map(|(id, player)| (*id, &player.component))
+            .chain(self.entities.iter().map(|(id, component)| (*id, component)))
+    }
+    
+    /// An id no player or entity has
+    fn new_id(&self) -> usize {
         loop {
-            thread::sleep(Duration::from_millis(1));
+            let id = Random::any();
+            if !self.players.contains_key(&id) && !self.entities.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+    
+    /// Adds a component to the world, clients learning about it with the next snapshot. Returns its id.
+    pub fn spawn(&mut self, component: GameComponent) -> usize {
+        let id = self.new_id();
+        self.entities.insert(id, component);
+        id
+    }
+    
+    pub fn despawn(&mut self, id: usize) {
+        self.entities.remove(&id);
+    }
+    
+    fn spawn_client(&mut self, mut stream: Box<dyn Transport>, mut protocol: Protocol, local: Option<SocketAddr>, id: usize, session: u64) {
+        let offer = local.and_then(|local| Self::offer_datagrams(local, &mut stream, &mut protocol));
+        let address = stream.peer();
+        let ip = stream.ip();
+        
+        let kicked = Arc::new(AtomicBool::new(false));
+        let traffic = Arc::new(Mutex::new(Traffic::default()));
+        let outbox = Arc::new(Mutex::new(Outbox::default()));
+        let acknowledged = Arc::new(Mutex::new(None));
+        let client = Client {
+            connection: Connection::new(stream, protocol),
+            offer,
+            id,
+            session,
+            inputs: Arc::clone(&self.inputs),
+            chat: Arc::clone(&self.chat),
+            outbox: Arc::clone(&outbox),
+            kicked: Arc::clone(&kicked),
+            traffic: Arc::clone(&traffic),
+            acknowledged: Arc::clone(&acknowledged),
+            limits: CommandLimits::default(),
+            idle_timeout: self.idle_timeout,
+            last_report: Instant::now(),
+            disconnected: false,
+            left: false
+        };
+        
+        let runner = match self.scheduling {
+            Scheduling::ThreadPerClient => Runner::Thread(thread::spawn(move || Self::tick_client(client))),
+            Scheduling::EventLoop => Runner::Polled(Box::new(client), None)
+        };
+        
+        self.clients.push(ClientHandle {
+            runner,
+            id,
+            address,
+            ip,
+            traffic,
+            outbox,
+            kicked,
+            acknowledged,
+            snapshots: SnapshotHistory::default()
+        });
+    }
+    
+    /// Opens a UDP socket for a client, which keeps using its stream if it cannot reach it
+    fn offer_datagrams(local: SocketAddr, stream: &mut Box<dyn Transport>, protocol: &mut Protocol) -> Option<UdpSocket> {
+        let socket = UdpSocket::bind((local.ip(), 0)).ok()?;
+        socket.set_nonblocking(true).ok()?;
+        protocol.send(stream, Command::UdpOffer(socket.local_addr().ok()?.port())).ok()?;
+        Some(socket)
+    }
+    
+    /// Removes the players that left, and keeps the slot of those whose connection dropped
+    fn handle_departures(&mut self) {
+        let (finished, running) = std::mem::take(&mut self.clients)
+            .into_iter()
+            .partition::<Vec<_>, _>(|client| client.is_finished());
+        self.clients = running;
+        
+        for client in finished {
+            let id = client.id;
             
-            let mut queue = broadcast_queue.lock().unwrap();
-            client.tick(&mut queue);
-            if client.disconnected {
-                Self::log(&format!("Client {} disconnected", client.id));
-                queue.push_back(Message {
-                    body: Command::Despawn(client.id),
-                    source: client.id,
-                    read_by: Vec::default()
-                });
+            if client.left() {
+                self.remove_player(id);
+            } else if let Some(player) = self.players.get_mut(&id) {
+                Self::log(&format!("Keeping the slot of client {id} for {} s", Self::SESSION_GRACE.as_secs()));
+                player.disconnected_since = Some(Instant::now());
+                player.inputs.clear();
+                if let Controller::Remote { input, .. } = &mut player.component.controller {
+                    *input = PlayerInput::default();
+                }
+            }
+        }
+    }
+    
+    fn expire_sessions(&mut self) {
+        let expired = self.players
+            .iter()
+            .filter(|(_, player)| player.disconnected_since.is_some_and(|since| since.elapsed() >= Self::SESSION_GRACE))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        
+        for id in expired {
+            Self::log(&format!("Session of client {id} expired"));
+            self.remove_player(id);
+        }
+    }
+    
+    fn remove_player(&mut self, id: usize) {
+        self.players.remove(&id);
+    }
+    
+    /// Queues `command` for every connected client but `except`
+    fn broadcast(&self, command: Command, except: Option<usize>) {
+        for client in self.clients.iter().filter(|client| Some(client.id) != except) {
+            client.outbox.lock().unwrap().push(command.clone());
+        }
+    }
+    
+    fn send_to(&self, id: usize, command: Command) {
+        if let Some(client) = self.clients.iter().find(|client| client.id == id) {
+            client.outbox.lock().unwrap().push(command);
+        }
+    }
+    
+    /// Buffers received inputs, then runs the fixed steps elapsed since the last call,
+    /// replicating what changed and acknowledging simulated inputs to their owner
+    fn simulate(&mut self) {
+        for (id, sequence, input) in self.inputs.lock().unwrap().drain(..) {
+            if let Some(player) = self.players.get_mut(&id) {
+                if player.inputs.len() == Self::MAX_BUFFERED_INPUTS {
+                    player.inputs.pop_front();
+                }
+                player.inputs.push_back((sequence, input.sanitized()));
+            }
+        }
+        
+        let before = self.players
+            .iter()
+            .map(|(id, player)| (*id, (player.component.body.position, player.acknowledged)))
+            .collect::<HashMap<_, _>>();
+        
+        let mut steps = 0;
+        while self.last_tick.elapsed() >= Self::TICK {
+            if steps == Self::MAX_STEPS_PER_UPDATE {
+                self.last_tick = Instant::now();
                 break;
             }
+            
+            for player in self.players.values_mut() {
+                player.step(&self.map, Self::TICK.as_secs_f32());
+            }
+            for component in self.entities.values_mut() {
+                component.step(&self.map, Self::TICK.as_secs_f32());
+            }
+            
+            self.last_tick += Self::TICK;
+            steps += 1;
+        }
+        
+        if steps > 0 {
+            self.send_snapshots();
+        }
+        
+        for (id, player) in self.players.iter() {
+            let body = &player.component.body;
+            let (position, acknowledged) = before.get(id).copied().unwrap_or_default();
+            
+            if position != body.position || acknowledged != player.acknowledged {
+                self.send_to(*id, Command::Acknowledge {
+                    sequence: player.acknowledged,
+                    position: body.position,
+                    velocity: body.velocity
+                });
+            }
+        }
+    }
+    
+    /// Sends every client what changed in its `Area` since the last snapshot it acknowledged.
+    /// Players are not told about their own character, they get `Command::Acknowledge` instead.
+    fn send_snapshots(&mut self) {
+        self.tick += 1;
+        let (tick, time) = (self.tick, self.time());
+        let world = self.components()
+            .map(|(id, component)| (id, component.body.position, EntityState::from(&Entity::from(component))))
+            .collect::<Vec<_>>();
+        
+        for client in self.clients.iter_mut() {
+            let Some(player) = self.players.get(&client.id) else { continue };
+            let area = Area::around(&self.map, player.component.body.position);
+            let seen = world
+                .iter()
+                .filter(|(id, position, _)| *id != client.id && area.contains(&self.map, *position))
+                .map(|(id, _, state)| (*id, state.clone()))
+                .collect::<World>();
+            
+            let acknowledged = *client.acknowledged.lock().unwrap();
+            if let Some(snapshot) = client.snapshots.next(tick, time, seen, acknowledged) {
+                client.outbox.lock().unwrap().push(Command::Snapshot(snapshot));
+            }
         }
     }
     
-    fn log(message: &str) {
+    /// Relays what players said to everyone under their name, and runs the commands of the host,
+    /// those starting with `/`. Messages too long or too frequent are refused with a `Command::SystemMessage`.
+    fn process_chat(&mut self) {
+        let messages = std::mem::take(&mut *self.chat.lock().unwrap());
+        for (id, text) in messages {
+            let Some(player) = self.players.get_mut(&id) else { continue };
+            
+            let text = text.chars().filter(|c| !c.is_control()).collect::<String>();
+            let text = text.trim();
+            if text.is_empty() {
+                continue;
+            }
+            
+            let name = player.name.clone();
+            let refusal = if !player.chat.take() {
+                Self::log(&format!("Client {id} ({name}) is sending messages too fast"));
+                Some(String::from("You are sending messages too fast"))
+            } else if text.chars().count() > Self::MAX_CHAT_LENGTH {
+                Some(format!("Messages are limited to {} characters", Self::MAX_CHAT_LENGTH))
+            } else if text.starts_with('/') && !player.admin {
+                Some(String::from("Commands are reserved to the host"))
+            } else {
+                None
+            };
+            
+            if let Some(refusal) = refusal {
+                self.send_to(id, Command::SystemMessage(refusal));
+            } else if let Some(line) = text.strip_prefix('/') {
+                Self::log(&format!("{name} > {line}"));
+                let answer = self.execute(line);
+                for line in answer.lines() {
+                    Self::log(line);
+                    self.send_to(id, Command::SystemMessage(line.to_string()));
+                }
+            } else {
+                Self::log(&format!("{name}: {text}"));
+                self.broadcast(Command::Chat { sender: name, text: text.to_string() }, None);
+            }
+        }
+    }
+    
+    /// Runs a console command, see `Order`, and returns what to answer
+    pub fn execute(&mut self, line: &str) -> String {
+        let order = match line.parse::<Order>() {
+            Ok(order) => order,
+            Err(e) => return format!("Error: {e}")
+        };
+        
+        match order {
+            Order::Help => Order::HELP.to_string(),
+            Order::List => self.list(),
+            Order::Kick(id) if self.players.contains_key(&id) => {
+                self.disconnect(id, DisconnectionReason::Kicked);
+                format!("Kicked client {id}")
+            },
+            Order::Ban(name) => self.ban(&name),
+            Order::Say(message) => {
+                Self::log(&format!("[Server] {message}"));
+                self.broadcast(Command::SystemMessage(message), None);
+                String::from("Sent")
+            },
+            Order::NewMap(seed) => {
+                let seed = seed.unwrap_or_else(Random::any);
+                let (width, height) = (self.map.width, self.map.height);
+                self.change_map(seed, width, height);
+                format!("Map {seed} of {width}x{height} rooms")
+            },
+            Order::Teleport(id, position) if self.players.contains_key(&id) => {
+                self.teleport(id, position);
+                format!("Moved client {id} to ({}, {})", position.x, position.y)
+            },
+            Order::Kick(id) | Order::Teleport(id, _) => format!("Error: no client {id}"),
+            Order::MaxPlayers(count) => {
+                self.max_players = count;
+                format!("Up to {count} players, {} in game", self.players.len())
+            },
+            Order::Shutdown => {
+                self.shutdown();
+                self.listeners.clear();
+                self.handshakes.clear();
+                self.stopped = true;
+                String::from("Server stopped")
+            }
+        }
+    }
+    
+    fn list(&self) -> String {
+        let mut players = self.players.iter().collect::<Vec<_>>();
+        players.sort_by_key(|(id, _)| **id);
+        
+        let mut lines = vec![format!("{}/{} players", players.len(), self.max_players)];
+        for (id, player) in players {
+            let position = player.component.body.position;
+            let state = match player.disconnected_since {
+                Some(since) => format!("disconnected for {} s", since.elapsed().as_secs()),
+                None => String::from("connected")
+            };
+            lines.push(format!("{id} {} at ({:.0}, {:.0}), {state}", player.name, position.x, position.y));
+        }
+        
+        lines.join("\n")
+    }
+    
+    fn ban(&mut self, name: &str) -> String {
+        let saved = self.bans.add(name);
+        
+        let banned = self.players
+            .iter()
+            .filter(|(_, player)| player.name == name)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in banned {
+            self.disconnect(id, DisconnectionReason::Banned);
+        }
+        
+        match saved {
+            Ok(()) => format!("Banned {name}"),
+            Err(e) => format!("Banned {name} until the server stops, the ban could not be saved: {e}")
+        }
+    }
+    
+    /// Closes a player's connection on purpose, which frees its slot
+    fn disconnect(&mut self, id: usize, reason: DisconnectionReason) {
+        Self::log(&format!("Disconnecting client {id}: {reason}"));
+        
+        if self.clients.iter().any(|client| client.id == id) {
+            self.send_to(id, Command::Disconnect(reason));
+        } else {
+            // Its connection already dropped, the slot was only kept for it to come back
+            self.remove_player(id);
+        }
+    }
+    
+    /// Replaces the map and sends every player back to the spawn point
+    fn change_map(&mut self, seed: usize, width: usize, height: usize) {
+        self.map_seed = seed;
+        self.map = Map::generate(width, height, seed);
+        // Map generation reseeds the generator, ids must not be derived from the public seed
+        Random::seed();
+        
+        self.broadcast(Command::ChangeMap { seed, width, height }, None);
+        for id in self.players.keys().copied().collect::<Vec<_>>() {
+            self.teleport(id, Map::spawn_point());
+        }
+    }
+    
+    /// Moves a player and stops it, its client replays the inputs the server did not simulate yet from there
+    fn teleport(&mut self, id: usize, position: Vec2) {
+        let Some(player) = self.players.get_mut(&id) else { return };
+        player.component.body.position = position;
+        player.component.body.velocity = Vec2::ZERO;
+        
+        let acknowledge = Command::Acknowledge { sequence: player.acknowledged, position, velocity: Vec2::ZERO };
+        self.send_to(id, acknowledge);
+    }
+    
+    /// Tells every client the server is going away, and waits for them to be told
+    pub fn shutdown(&mut self) {
+        Self::log("Shutting down");
+        self.broadcast(Command::Disconnect(DisconnectionReason::Shutdown), None);
+        
+        let deadline = Instant::now() + Self::SHUTDOWN_TIMEOUT;
+        while !self.clients.is_empty() && Instant::now() < deadline {
+            self.step_clients();
+            self.handle_departures();
+            thread::sleep(Self::POLL_INTERVAL);
+        }
+    }
+    
+    /// Serves the connections of `Scheduling::EventLoop`, once the simulation queued what they have to send
+    fn step_clients(&mut self) {
+        for client in self.clients.iter_mut() {
+            if let Runner::Polled(client, outcome @ None) = &mut client.runner {
+                *outcome = client.step();
+            }
+        }
+    }
+    
+    /// Blocks until a socket needs the server or the next simulation step is due.
+    /// Calling `update` then `wait` in a loop serves every client of `Scheduling::EventLoop` from a single thread.
+    pub fn wait(&self) {
+        let mut interests = Vec::new();
+        // Sources without a socket have to be checked periodically
+        let mut periodic = false;
+        
+        for listener in self.listeners.iter() {
+            match listener.descriptor() {
+                Some(descriptor) => interests.push(Interest { descriptor, write: false }),
+                None => periodic = true
+            }
+        }
+        
+        for handshake in self.handshakes.iter() {
+            match handshake.stream.descriptor() {
+                Some(descriptor) => interests.push(Interest { descriptor, write: handshake.protocol.backlogged() }),
+                None => periodic = true
+            }
+        }
+        
+        for client in self.clients.iter() {
+            if let Runner::Polled(client, None) = &client.runner {
+                let ready = client.interests();
+                periodic |= ready.is_empty();
+                interests.extend(ready);
+            }
+        }
+        
+        let mut timeout = (self.last_tick + Self::TICK).saturating_duration_since(Instant::now());
+        if periodic {
+            timeout = timeout.min(Self::POLL_INTERVAL);
+        }
+        
+        readiness::wait(&interests, timeout);
+    }
+    
+    /// Milliseconds elapsed since the server started, used to timestamp positions
+    fn time(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+    
+    /// Runs a client's connection until it ends, returning whether the client left on purpose
+    fn tick_client(mut client: Client) -> bool {
+        loop {
+            thread::sleep(Duration::from_millis(1));
+            
+            if let Some(left) = client.step() {
+                return left;
+            }
+        }
+    }
+    
+    pub fn log(message: &str) {
         let (hour, minute, second) = Time::hour();
+        let time = format!("{}:{}:{}", base_format(hour, 10), base_format(minute, 10), base_format(second, 10));
     
-        println!(
-            "\r[{}:{}:{}] > {message}                                                ",
-            base_format(hour, 10),
-            base_format(minute, 10),
-            base_format(second, 10)
-        )
+        println!("\r[{time}] > {message}                                                ");
+        
+        let mut log = LOG.lock().unwrap();
+        if log.len() == Self::LOG_LENGTH {
+            log.pop_front();
+        }
+        log.push_back(format!("[{time}] {message}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    use std::net::TcpStream;
+    
+    use crate::game::body::Body;
+    use crate::game::controller::ControllerKind;
+    use crate::game::map::Room;
+    use crate::network::snapshot::{ Change, ReceivedSnapshots, Snapshot };
+    
+    fn hello(server: &GameServer, name: &str, session: Option<u64>) -> (TcpStream, Protocol) {
+        let mut stream = TcpStream::connect(server.listeners[0].local_address().unwrap()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        
+        let mut protocol = Protocol::new();
+        let hello = Command::Hello { version: Protocol::VERSION, name: name.to_string(), build: String::new(), session };
+        protocol.send(&mut stream, hello).unwrap();
+        
+        (stream, protocol)
+    }
+    
+    fn answer(server: &mut GameServer, stream: &mut TcpStream, protocol: &mut Protocol) -> Command {
+        for _ in 0..500 {
+            server.update();
+            if let Ok(command) = protocol.reception(stream) {
+                return command;
+            }
+        }
+        panic!("The server did not answer");
+    }
+    
+    #[test]
+    fn dropped_players_resume_their_session() {
+        let mut server = GameServer::new("127.0.0.1:0").unwrap();
+        
+        let (mut stream, mut protocol) = hello(&server, "Alice", None);
+        let Command::Welcome(id, token) = answer(&mut server, &mut stream, &mut protocol) else { panic!() };
+        server.players.get_mut(&id).unwrap().component.body.position = vec2(123.0, 45.0);
+        
+        // The connection drops without the client leaving
+        drop(stream);
+        for _ in 0..500 {
+            server.update();
+            if server.players[&id].disconnected_since.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(server.players[&id].disconnected_since.is_some());
+        
+        // The slot is reserved
+        let (mut stream, mut protocol) = hello(&server, "Alice", None);
+        assert_eq!(answer(&mut server, &mut stream, &mut protocol), Command::Rejected(RejectionReason::NameTaken));
+        
+        let (mut stream, mut protocol) = hello(&server, "Alice", Some(token));
+        assert_eq!(answer(&mut server, &mut stream, &mut protocol), Command::Welcome(id, token));
+        assert_eq!(server.players[&id].disconnected_since, None);
+        assert_eq!(server.players[&id].component.body.position, vec2(123.0, 45.0));
+    }
+    
+    #[test]
+    fn datagrams_take_over_after_the_handshake() {
+        let mut server = GameServer::new("127.0.0.1:0").unwrap();
+        
+        let (mut stream, mut protocol) = hello(&server, "Alice", None);
+        let Command::Welcome(_, token) = answer(&mut server, &mut stream, &mut protocol) else { panic!() };
+        let port = loop {
+            if let Command::UdpOffer(port) = answer(&mut server, &mut stream, &mut protocol) {
+                break port;
+            }
+        };
+        
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(("127.0.0.1", port)).unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let mut datagrams = DatagramProtocol::new();
+        let introduction = Command::Hello { version: Protocol::VERSION, name: String::new(), build: String::new(), session: Some(token) };
+        datagrams.send(&socket, introduction).unwrap();
+        
+        let mut received = |server: &mut GameServer| {
+            for _ in 0..500 {
+                server.update();
+                match datagrams.reception(&socket) {
+                    Ok(Command::Ping(_)) | Err(_) => {},
+                    Ok(command) => return command
+                }
+            }
+            panic!("Nothing came through UDP");
+        };
+        assert!(matches!(received(&mut server), Command::Welcome(..)));
+        
+        // Snapshots now come through UDP
+        let (mut other, mut other_protocol) = hello(&server, "Bob", None);
+        let Command::Welcome(bob, _) = answer(&mut server, &mut other, &mut other_protocol) else { panic!() };
+        loop {
+            if let Command::Snapshot(snapshot) = received(&mut server) && snapshot.changes.iter().any(|(id, _)| *id == bob) {
+                break;
+            }
+        }
+    }
+    
+    #[test]
+    fn clients_sending_garbage_are_disconnected() {
+        let mut server = GameServer::new("127.0.0.1:0").unwrap();
+        
+        let (mut stream, mut protocol) = hello(&server, "Alice", None);
+        let Command::Welcome(id, _) = answer(&mut server, &mut stream, &mut protocol) else { panic!() };
+        for _ in 0..Connection::MAX_MALFORMED {
+            protocol.send(&mut stream, Command::Unknown).unwrap();
+        }
+        
+        loop {
+            if let Command::Disconnect(reason) = answer(&mut server, &mut stream, &mut protocol) {
+                assert_eq!(reason, DisconnectionReason::Malformed);
+                break;
+            }
+        }
+        for _ in 0..500 {
+            server.update();
+            if !server.players.contains_key(&id) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        panic!("The client was not removed");
+    }
+    
+    #[test]
+    fn clients_are_told_about_shutdowns() {
+        let mut server = GameServer::new("127.0.0.1:0").unwrap().with_scheduling(Scheduling::EventLoop).with_map(7, 3, 2);
+        
+        let (mut stream, mut protocol) = hello(&server, "Alice", None);
+        assert!(matches!(answer(&mut server, &mut stream, &mut protocol), Command::Welcome(..)));
+        assert_eq!(answer(&mut server, &mut stream, &mut protocol), Command::ChangeMap { seed: 7, width: 3, height: 2 });
+        
+        server.shutdown();
+        assert!(server.clients.is_empty());
+        loop {
+            match protocol.reception(&mut stream) {
+                Ok(Command::Disconnect(reason)) => break assert_eq!(reason, DisconnectionReason::Shutdown),
+                Err(ProtocolError::Disconnection) => panic!("The connection was closed without a reason"),
+                _ => {}
+            }
+        }
+    }
+    
+    #[test]
+    fn console_kicks_and_bans_players() {
+        let path = std::env::temp_dir().join(format!("bored-server-bans-{}.txt", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut server = GameServer::new("127.0.0.1:0").unwrap().with_bans(Bans::load(&path).unwrap());
+        
+        let (mut stream, mut protocol) = hello(&server, "Alice", None);
+        let Command::Welcome(id, _) = answer(&mut server, &mut stream, &mut protocol) else { panic!() };
+        assert_eq!(server.execute(&format!("kick {id}")), format!("Kicked client {id}"));
+        assert!(server.execute("kick 0").starts_with("Error"));
+        loop {
+            if let Command::Disconnect(reason) = answer(&mut server, &mut stream, &mut protocol) {
+                assert_eq!(reason, DisconnectionReason::Kicked);
+                break;
+            }
+        }
+        
+        assert_eq!(server.execute("ban Mallory"), "Banned Mallory");
+        drop(server);
+        
+        // Bans outlive the server
+        let mut server = GameServer::new("127.0.0.1:0").unwrap().with_bans(Bans::load(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        let (mut stream, mut protocol) = hello(&server, "Mallory", None);
+        assert_eq!(answer(&mut server, &mut stream, &mut protocol), Command::Rejected(RejectionReason::Banned));
+        
+        assert_eq!(server.execute("shutdown"), "Server stopped");
+        assert!(server.is_stopped() && server.listeners.is_empty());
+    }
+    
+    #[test]
+    fn entities_are_replicated_field_by_field() {
+        let mut server = GameServer::new("127.0.0.1:0").unwrap().with_map(3, 3, 3);
+        let monster = server.spawn(GameComponent::from(GameObject::Monster)
+            .with_body(Body::default().with_position(Map::spawn_point()).with_friction_factor(0.9))
+            .with_controller(Controller::Monster));
+        
+        let (mut stream, mut protocol) = hello(&server, "Alice", None);
+        let mut received = ReceivedSnapshots::default();
+        // Acknowledges every snapshot until one leads to a world `wanted` accepts
+        let mut next = |server: &mut GameServer, wanted: &dyn Fn(&Snapshot, &World) -> bool| loop {
+            let Command::Snapshot(snapshot) = answer(server, &mut stream, &mut protocol) else { continue };
+            let Some(world) = received.receive(&snapshot) else { continue };
+            protocol.send(&mut stream, Command::SnapshotAck(snapshot.tick)).unwrap();
+            if wanted(&snapshot, world) {
+                break (snapshot, world.clone());
+            }
+        };
+        
+        // The first snapshot is a full one
+        let (snapshot, world) = next(&mut server, &|_, _| true);
+        assert_eq!(snapshot.baseline, None);
+        assert!(matches!(snapshot.changes[..], [(id, Change::Spawn(_))] if id == monster));
+        assert_eq!((world[&monster].object.clone(), world[&monster].controller), (GameObject::Monster, ControllerKind::Monster));
+        
+        // Monsters walk on their own, only their motion changes
+        let (snapshot, _) = next(&mut server, &|snapshot, _| snapshot.baseline.is_some());
+        assert!(matches!(&snapshot.changes[..], [(id, Change::Update(delta))] if *id == monster && delta.object.is_none()));
+        
+        let checkpoint = GameComponent {
+            body: Body::default().with_size(vec2(20.0, 20.0)),
+            object: GameObject::CheckPoint { priority: 2 },
+            controller: Controller::BrainDead
+        };
+        let id = server.spawn(checkpoint);
+        let (_, world) = next(&mut server, &|_, world| world.contains_key(&id));
+        assert_eq!(world[&id].to_entity(), Entity {
+            object: GameObject::CheckPoint { priority: 2 },
+            controller: ControllerKind::BrainDead,
+            position: Vec2::ZERO,
+            velocity: Vec2::ZERO,
+            size: vec2(20.0, 20.0)
+        });
+        
+        server.entities.get_mut(&id).unwrap().object = GameObject::CheckPoint { priority: 5 };
+        let (snapshot, _) = next(&mut server, &|_, world| world[&id].object == GameObject::CheckPoint { priority: 5 });
+        assert!(snapshot.baseline.is_some());
+        
+        server.despawn(monster);
+        let (snapshot, _) = next(&mut server, &|_, world| !world.contains_key(&monster));
+        assert!(snapshot.changes.contains(&(monster, Change::Despawn)));
+    }
+    
+    #[test]
+    fn entities_only_reach_the_players_nearby() {
+        let mut server = GameServer::new("127.0.0.1:0").unwrap().with_map(5, 5, 3);
+        let room = vec2(Room::WIDTH, Room::HEIGHT);
+        let far = Map::spawn_point() + vec2(2.0 * room.x, 0.0);
+        let checkpoint = server.spawn(GameComponent {
+            body: Body::default().with_position(far),
+            object: GameObject::CheckPoint { priority: 1 },
+            controller: Controller::BrainDead
+        });
+        
+        let (mut stream, mut protocol) = hello(&server, "Alice", None);
+        let mut received = ReceivedSnapshots::default();
+        let mut next = |server: &mut GameServer, wanted: &dyn Fn(&World) -> bool| loop {
+            let Command::Snapshot(snapshot) = answer(server, &mut stream, &mut protocol) else { continue };
+            let Some(world) = received.receive(&snapshot) else { continue };
+            protocol.send(&mut stream, Command::SnapshotAck(snapshot.tick)).unwrap();
+            if wanted(world) {
+                break snapshot;
+            }
+        };
+        
+        let snapshot = next(&mut server, &|_| true);
+        assert!(snapshot.changes.is_empty());
+        
+        // Coming into the next room spawns it, going away again despawns it
+        server.entities.get_mut(&checkpoint).unwrap().body.position = Map::spawn_point() + vec2(room.x, 0.0);
+        let snapshot = next(&mut server, &|world| world.contains_key(&checkpoint));
+        assert!(matches!(snapshot.changes[..], [(id, Change::Spawn(_))] if id == checkpoint));
+        
+        server.entities.get_mut(&checkpoint).unwrap().body.position = far;
+        let snapshot = next(&mut server, &|world| !world.contains_key(&checkpoint));
+        assert_eq!(snapshot.changes, vec![(checkpoint, Change::Despawn)]);
+    }
+    
+    #[test]
+    fn flooding_clients_are_disconnected() {
+        let mut server = GameServer::new("127.0.0.1:0").unwrap();
+        
+        let (mut stream, mut protocol) = hello(&server, "Alice", None);
+        assert!(matches!(answer(&mut server, &mut stream, &mut protocol), Command::Welcome(..)));
+        for _ in 0..100 {
+            protocol.send(&mut stream, Command::Chat { sender: String::new(), text: String::from("spam") }).unwrap();
+        }
+        
+        loop {
+            if let Command::Disconnect(reason) = answer(&mut server, &mut stream, &mut protocol) {
+                break assert_eq!(reason, DisconnectionReason::Flooding);
+            }
+        }
+        
+        // Frames too large for any command are not even read
+        let (mut stream, mut protocol) = hello(&server, "Bob", None);
+        assert!(matches!(answer(&mut server, &mut stream, &mut protocol), Command::Welcome(..)));
+        protocol.send(&mut stream, Command::Chat { sender: String::new(), text: "a".repeat(10_000) }).unwrap();
+        loop {
+            if let Command::Disconnect(reason) = answer(&mut server, &mut stream, &mut protocol) {
+                break assert_eq!(reason, DisconnectionReason::Flooding);
+            }
+        }
+    }
+    
+    #[test]
+    fn connections_per_address_are_capped() {
+        let mut server = GameServer::new("127.0.0.1:0").unwrap().with_max_connections_per_ip(2);
+        
+        let (mut alice, mut alice_protocol) = hello(&server, "Alice", None);
+        assert!(matches!(answer(&mut server, &mut alice, &mut alice_protocol), Command::Welcome(..)));
+        // Connections count before their handshake
+        let _pending = TcpStream::connect(server.listeners[0].local_address().unwrap()).unwrap();
+        for _ in 0..100 {
+            server.update();
+            if !server.handshakes.is_empty() {
+                break;
+            }
+        }
+        
+        let (mut stream, mut protocol) = hello(&server, "Bob", None);
+        assert_eq!(answer(&mut server, &mut stream, &mut protocol), Command::Rejected(RejectionReason::TooManyConnections));
+        
+        // In-process connections are not counted
+        let _host = server.connector().connect().unwrap();
+        let pending = server.handshakes.len();
+        server.update();
+        assert_eq!(server.handshakes.len(), pending + 1);
+    }
+    
+    #[test]
+    fn ping_floods_are_disconnected() {
+        let mut server = GameServer::new("127.0.0.1:0").unwrap();
+        
+        let (mut stream, mut protocol) = hello(&server, "Alice", None);
+        assert!(matches!(answer(&mut server, &mut stream, &mut protocol), Command::Welcome(..)));
+        
+        // Answered by the protocol itself, each of them would cost the server a pong. Few enough
+        // to be read at once: closing a socket with unread bytes resets it, losing the disconnection.
+        for time in 0..20 {
+            protocol.send(&mut stream, Command::Ping(time)).unwrap();
+        }
+        
+        loop {
+            if let Command::Disconnect(reason) = answer(&mut server, &mut stream, &mut protocol) {
+                break assert_eq!(reason, DisconnectionReason::Flooding);
+            }
+        }
+    }
+    
+    #[test]
+    fn chat_is_relayed_and_limited() {
+        let mut server = GameServer::new("127.0.0.1:0").unwrap();
+        
+        let (mut alice, mut alice_protocol) = hello(&server, "Alice", None);
+        let Command::Welcome(id, _) = answer(&mut server, &mut alice, &mut alice_protocol) else { panic!() };
+        let (mut bob, mut bob_protocol) = hello(&server, "Bob", None);
+        assert!(matches!(answer(&mut server, &mut bob, &mut bob_protocol), Command::Welcome(..)));
+        
+        let next_chat = |server: &mut GameServer, stream: &mut TcpStream, protocol: &mut Protocol| loop {
+            if let command @ (Command::Chat { .. } | Command::SystemMessage(_)) = answer(server, stream, protocol) {
+                break command;
+            }
+        };
+        
+        // The sender cannot pretend to be someone else, nor slip control characters in
+        let said = Command::Chat { sender: String::from("Server"), text: String::from(" hi\u{7} bob ") };
+        alice_protocol.send(&mut alice, said).unwrap();
+        let relayed = Command::Chat { sender: String::from("Alice"), text: String::from("hi bob") };
+        assert_eq!(next_chat(&mut server, &mut bob, &mut bob_protocol), relayed);
+        assert_eq!(next_chat(&mut server, &mut alice, &mut alice_protocol), relayed);
+        
+        let long = "a".repeat(GameServer::MAX_CHAT_LENGTH + 1);
+        alice_protocol.send(&mut alice, Command::Chat { sender: String::new(), text: long }).unwrap();
+        assert!(matches!(next_chat(&mut server, &mut alice, &mut alice_protocol), Command::SystemMessage(_)));
+        
+        // Only the host may run commands
+        alice_protocol.send(&mut alice, Command::Chat { sender: String::new(), text: String::from("/shutdown") }).unwrap();
+        let refusal = Command::SystemMessage(String::from("Commands are reserved to the host"));
+        assert_eq!(next_chat(&mut server, &mut alice, &mut alice_protocol), refusal);
+        assert!(!server.is_stopped());
+        
+        // The burst was spent by the messages above
+        for _ in 0..GameServer::CHAT_BURST {
+            alice_protocol.send(&mut alice, Command::Chat { sender: String::new(), text: String::from("spam") }).unwrap();
+        }
+        let mut relayed = 0;
+        loop {
+            match next_chat(&mut server, &mut alice, &mut alice_protocol) {
+                Command::Chat { .. } => relayed += 1,
+                Command::SystemMessage(text) => break assert_eq!(text, "You are sending messages too fast"),
+                _ => unreachable!()
+            }
+        }
+        assert!(relayed < GameServer::CHAT_BURST);
+        assert!(server.players.contains_key(&id));
+    }
+    
+    #[test]
+    fn host_runs_commands_from_the_chat() {
+        let mut server = GameServer::offline().with_scheduling(Scheduling::EventLoop);
+        let (mut stream, mut protocol) = (server.connector().connect().unwrap(), Protocol::new());
+        let hello = Command::Hello { version: Protocol::VERSION, name: String::from("Host"), build: String::new(), session: None };
+        protocol.send(&mut stream, hello).unwrap();
+        
+        let said = Command::Chat { sender: String::new(), text: String::from("/list") };
+        let mut said = Some(said);
+        for _ in 0..500 {
+            server.update();
+            match protocol.reception(&mut stream) {
+                Ok(Command::Welcome(..)) => protocol.send(&mut stream, said.take().unwrap()).unwrap(),
+                Ok(Command::SystemMessage(text)) => return assert_eq!(text, format!("1/{} players", GameServer::DEFAULT_MAX_PLAYERS)),
+                _ => {}
+            }
+        }
+        panic!("The command was not answered");
+    }
+    
+    #[test]
+    fn event_loop_serves_every_client_from_one_thread() {
+        let mut server = GameServer::new("127.0.0.1:0").unwrap().with_scheduling(Scheduling::EventLoop);
+        
+        let (mut alice, mut alice_protocol) = hello(&server, "Alice", None);
+        assert!(matches!(answer(&mut server, &mut alice, &mut alice_protocol), Command::Welcome(..)));
+        let (mut bob, mut bob_protocol) = hello(&server, "Bob", None);
+        assert!(matches!(answer(&mut server, &mut bob, &mut bob_protocol), Command::Welcome(..)));
+        assert!(server.clients.iter().all(|client| matches!(client.runner, Runner::Polled(..))));
+        
+        let input = PlayerInput { slide: vec2(1.0, 0.0), ..Default::default() };
+        alice_protocol.send(&mut alice, Command::Input(1, input)).unwrap();
+        for _ in 0..500 {
+            server.update();
+            server.wait();
+            if let Ok(Command::Acknowledge { sequence: 1, .. }) = alice_protocol.reception(&mut alice) {
+                return;
+            }
+        }
+        panic!("The input was not simulated");
+    }
+    
+    /// CPU time used by the whole process so far
+    fn processor_time() -> Duration {
+        unsafe extern "C" {
+            fn clock() -> std::ffi::c_long;
+        }
+        
+        // `CLOCKS_PER_SEC` is a million on every POSIX system
+        Duration::from_micros(unsafe { clock() } as u64)
+    }
+    
+    /// Share of a core used by a server and `count` TCP clients sending inputs at 60 Hz, the clients running from a single thread
+    fn processor_usage(scheduling: Scheduling, count: usize) -> f64 {
+        const DURATION: Duration = Duration::from_secs(5);
+        
+        let mut server = GameServer::new("127.0.0.1:0").unwrap().with_scheduling(scheduling);
+        server.max_players = count;
+        server.max_connections_per_ip = count;
+        
+        let mut clients = (0..count)
+            .map(|n| {
+                let (mut stream, mut protocol) = hello(&server, &format!("Client {n}"), None);
+                assert!(matches!(answer(&mut server, &mut stream, &mut protocol), Command::Welcome(..)));
+                stream.set_nonblocking(true).unwrap();
+                (stream, protocol)
+            })
+            .collect::<Vec<_>>();
+        
+        let running = Arc::new(AtomicBool::new(true));
+        let driver = {
+            let running = Arc::clone(&running);
+            thread::spawn(move || {
+                let mut sequence = 0;
+                while running.load(Ordering::Relaxed) {
+                    sequence += 1;
+                    let input = PlayerInput { slide: Vec2::from_angle(sequence as f32 / 30.0), ..Default::default() };
+                    
+                    for (stream, protocol) in clients.iter_mut() {
+                        let _ = protocol.send(stream, Command::Input(sequence, input));
+                        while let Ok(_) | Err(ProtocolError::OutdatedPackage) = protocol.reception(stream) {}
+                        let _ = protocol.heartbeat(stream);
+                    }
+                    
+                    thread::sleep(GameServer::TICK);
+                }
+            })
+        };
+        
+        let (start, used) = (Instant::now(), processor_time());
+        while start.elapsed() < DURATION {
+            server.update();
+            server.wait();
+        }
+        let used = processor_time() - used;
+        
+        running.store(false, Ordering::Relaxed);
+        driver.join().unwrap();
+        assert_eq!(server.clients.len(), count);
+        
+        100.0 * used.as_secs_f64() / start.elapsed().as_secs_f64()
+    }
+    
+    /// Run with `cargo test --release scheduling -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn scheduling_benchmark() {
+        println!("clients | thread per client | event loop");
+        for count in [2, 8, 32] {
+            println!(
+                "{count:>7} | {:>16.1}% | {:>9.1}%",
+                processor_usage(Scheduling::ThreadPerClient, count),
+                processor_usage(Scheduling::EventLoop, count)
+            );
+        }
     }
 }
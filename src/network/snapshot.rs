@@ -0,0 +1,625 @@
+use macroquad::prelude::*;
+
+use std::collections::{ HashMap, VecDeque };
+
+use crate::game::controller::ControllerKind;
+use crate::game::object::GameObject;
+
+use super::codec::{ Reader, Shareable };
+use super::replication::Entity;
+use super::FormatError;
+
+/// Positions are sent in sixteenths of a unit
+const POSITION_STEP: f32 = 1.0 / 16.0;
+/// Velocities, in units per step, are sent in 256ths of a unit
+const VELOCITY_STEP: f32 = 1.0 / 256.0;
+
+/// An `Entity` as snapshots carry it, its position and velocity quantised
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntityState {
+    pub object: GameObject,
+    pub controller: ControllerKind,
+    pub position: IVec2,
+    pub velocity: IVec2,
+    pub size: Vec2
+}
+
+impl From<&Entity> for EntityState {
+    fn from(entity: &Entity) -> Self {
+        Self {
+            object: entity.object.clone(),
+            controller: entity.controller,
+            position: (entity.position / POSITION_STEP).round().as_ivec2(),
+            velocity: (entity.velocity / VELOCITY_STEP).round().as_ivec2(),
+            size: entity.size
+        }
+    }
+}
+
+impl EntityState {
+    pub fn to_entity(&self) -> Entity {
+        Entity {
+            object: self.object.clone(),
+            controller: self.controller,
+            position: self.position.as_vec2() * POSITION_STEP,
+            velocity: self.velocity.as_vec2() * VELOCITY_STEP,
+            size: self.size
+        }
+    }
+}
+
+/// What a client knows of the world at a given tick, by entity id
+pub type World = HashMap<usize, EntityState>;
+
+/// Fields of an `EntityState` that changed, position and velocity being differences with the baseline
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StateDelta {
+    pub object: Option<GameObject>,
+    pub controller: Option<ControllerKind>,
+    pub position: Option<IVec2>,
+    pub velocity: Option<IVec2>,
+    pub size: Option<Vec2>
+}
+
+impl StateDelta {
+    pub fn between(old: &EntityState, new: &EntityState) -> Self {
+        Self {
+            object: (old.object != new.object).then(|| new.object.clone()),
+            controller: (old.controller != new.controller).then_some(new.controller),
+            position: (old.position != new.position).then(|| new.position - old.position),
+            velocity: (old.velocity != new.velocity).then(|| new.velocity - old.velocity),
+            size: (old.size != new.size).then_some(new.size)
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn apply(&self, state: &mut EntityState) {
+        if let Some(object) = &self.object {
+            state.object = object.clone();
+        }
+        if let Some(controller) = self.controller {
+            state.controller = controller;
+        }
+        if let Some(position) = self.position {
+            state.position += position;
+        }
+        if let Some(velocity) = self.velocity {
+            state.velocity += velocity;
+        }
+        if let Some(size) = self.size {
+            state.size = size;
+        }
+    }
+}
+
+/// What happened to an entity since the baseline of a `Snapshot`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    Spawn (EntityState),
+    Update (StateDelta),
+    Despawn
+}
+
+/// The world as of `tick`, sent as the changes since the `baseline` tick the client acknowledged.
+/// A snapshot without baseline is a full one, every entity being spawned.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub tick: u32,
+    pub baseline: Option<u32>,
+    /// Server time of the snapshot, in milliseconds
+    pub time: u64,
+    pub changes: Vec<(usize, Change)>
+}
+
+impl Snapshot {
+    /// Changes turning `baseline` into `world`, an empty baseline giving a full snapshot
+    pub fn between(tick: u32, time: u64, baseline: Option<(u32, &World)>, world: &World) -> Self {
+        let empty = World::new();
+        let (baseline, old) = baseline.map_or((None, &empty), |(tick, world)| (Some(tick), world));
+
+        let mut changes = world
+            .iter()
+            .filter_map(|(id, state)| match old.get(id) {
+                None => Some((*id, Change::Spawn(state.clone()))),
+                Some(previous) => {
+                    let delta = StateDelta::between(previous, state);
+                    (!delta.is_empty()).then_some((*id, Change::Update(delta)))
+                }
+            })
+            .chain(old.keys().filter(|id| !world.contains_key(id)).map(|id| (*id, Change::Despawn)))
+            .collect::<Vec<_>>();
+        changes.sort_by_key(|(id, _)| *id);
+
+        Self { tick, baseline, time, changes }
+    }
+
+    /// The world this snapshot describes, `baseline` being the world of its baseline tick
+    pub fn apply(&self, baseline: &World) -> World {
+        let mut world = if self.baseline.is_some() { baseline.clone() } else { World::new() };
+
+        for (id, change) in self.changes.iter() {
+            match change {
+                Change::Spawn(state) => {
+                    world.insert(*id, state.clone());
+                },
+                Change::Update(delta) => {
+                    if let Some(state) = world.get_mut(id) {
+                        delta.apply(state);
+                    }
+                },
+                Change::Despawn => {
+                    world.remove(id);
+                }
+            }
+        }
+
+        world
+    }
+}
+
+/// Snapshots the server sent a client and that may serve as baselines, oldest first
+#[derive(Debug, Default)]
+pub struct SnapshotHistory {
+    sent: VecDeque<(u32, World)>
+}
+
+impl SnapshotHistory {
+    /// Snapshots sent without an acknowledgement before the client gets a full one again
+    pub const MAX_UNACKNOWLEDGED: usize = 32;
+
+    /// Snapshot of `world` to send for `tick`, relative to the `acknowledged` one if it is still known.
+    /// `None` if nothing changed since then.
+    pub fn next(&mut self, tick: u32, time: u64, world: World, acknowledged: Option<u32>) -> Option<Snapshot> {
+        // Baselines older than the acknowledged one will never be used again
+        if let Some(acknowledged) = acknowledged {
+            while self.sent.front().is_some_and(|(sent, _)| *sent < acknowledged) {
+                self.sent.pop_front();
+            }
+        }
+
+        let baseline = self.sent.iter().find(|(sent, _)| Some(*sent) == acknowledged).map(|(tick, world)| (*tick, world));
+        let snapshot = Snapshot::between(tick, time, baseline, &world);
+        if snapshot.baseline.is_some() && snapshot.changes.is_empty() {
+            return None;
+        }
+
+        if self.sent.len() == Self::MAX_UNACKNOWLEDGED {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((tick, world));
+        Some(snapshot)
+    }
+}
+
+/// Worlds a client rebuilt from the snapshots it received, which the next ones are relative to
+#[derive(Debug, Default)]
+pub struct ReceivedSnapshots {
+    worlds: VecDeque<(u32, World)>
+}
+
+impl ReceivedSnapshots {
+    /// The world `snapshot` describes, unless it is older than the last one or its baseline was forgotten
+    pub fn receive(&mut self, snapshot: &Snapshot) -> Option<&World> {
+        if self.worlds.back().is_some_and(|(newest, _)| *newest >= snapshot.tick) {
+            return None;
+        }
+
+        let world = match snapshot.baseline {
+            Some(baseline) => {
+                let (_, world) = self.worlds.iter().find(|(tick, _)| *tick == baseline)?;
+                snapshot.apply(world)
+            },
+            None => snapshot.apply(&World::new())
+        };
+
+        // The server only builds on snapshots it knows we have, and never goes back
+        if let Some(baseline) = snapshot.baseline {
+            while self.worlds.front().is_some_and(|(tick, _)| *tick < baseline) {
+                self.worlds.pop_front();
+            }
+        }
+        if self.worlds.len() == SnapshotHistory::MAX_UNACKNOWLEDGED {
+            self.worlds.pop_front();
+        }
+
+        self.worlds.push_back((snapshot.tick, world));
+        self.worlds.back().map(|(_, world)| world)
+    }
+
+    pub fn clear(&mut self) {
+        self.worlds.clear();
+    }
+}
+
+/// Writes values on as few bits as they need, most significant bit first
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits used in the last byte, 8 meaning it is full
+    used: u32
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u64, bits: u32) {
+        for bit in (0..bits).rev() {
+            if self.used.is_multiple_of(8) {
+                self.bytes.push(0);
+                self.used = 0;
+            }
+            if value >> bit & 1 == 1 {
+                *self.bytes.last_mut().unwrap() |= 0x80 >> self.used;
+            }
+            self.used += 1;
+        }
+    }
+
+    fn flag(&mut self, value: bool) {
+        self.write(value as u64, 1);
+    }
+
+    /// The length of `value` in bits on `length_bits`, then its significant bits
+    fn number(&mut self, value: u64, length_bits: u32) {
+        let length = u64::BITS - value.leading_zeros();
+        self.write(length as u64, length_bits);
+        self.write(value, length);
+    }
+
+    /// Zigzag encoded so that small magnitudes take few bits whatever their sign
+    fn signed(&mut self, value: i32) {
+        self.number(((value << 1) ^ (value >> 31)) as u32 as u64, 6);
+    }
+
+    fn vector(&mut self, vector: IVec2) {
+        self.signed(vector.x);
+        self.signed(vector.y);
+    }
+
+    fn float(&mut self, value: f32) {
+        self.write(value.to_bits() as u64, 32);
+    }
+
+    fn object(&mut self, object: &GameObject) {
+        match object {
+            GameObject::Player => self.write(0, 3),
+            GameObject::Monster => self.write(1, 3),
+            GameObject::CheckPoint { priority } => {
+                self.write(2, 3);
+                self.number(*priority as u64, 7);
+            },
+            GameObject::Wall => self.write(3, 3),
+            GameObject::Projectile => self.write(4, 3)
+        }
+    }
+
+    fn controller(&mut self, controller: ControllerKind) {
+        self.write(match controller {
+            ControllerKind::Player => 0,
+            ControllerKind::Monster => 1,
+            ControllerKind::BrainDead => 2
+        }, 2);
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn read(&mut self, bits: u32) -> Result<u64, FormatError> {
+        let available = self.bytes.len() * 8 - self.position;
+        if bits as usize > available {
+            return Err(FormatError::Truncated { expected: bits as usize, available });
+        }
+
+        let mut value = 0;
+        for _ in 0..bits {
+            let bit = self.bytes[self.position / 8] >> (7 - self.position % 8) & 1;
+            value = value << 1 | bit as u64;
+            self.position += 1;
+        }
+        Ok(value)
+    }
+
+    fn flag(&mut self) -> Result<bool, FormatError> {
+        Ok(self.read(1)? == 1)
+    }
+
+    fn number(&mut self, length_bits: u32) -> Result<u64, FormatError> {
+        let length = self.read(length_bits)? as u32;
+        if length > u64::BITS {
+            return Err(FormatError::InvalidValue);
+        }
+        self.read(length)
+    }
+
+    fn signed(&mut self) -> Result<i32, FormatError> {
+        let zigzag = u32::try_from(self.number(6)?).map_err(|_| FormatError::InvalidValue)?;
+        Ok((zigzag >> 1) as i32 ^ -((zigzag & 1) as i32))
+    }
+
+    fn vector(&mut self) -> Result<IVec2, FormatError> {
+        Ok(ivec2(self.signed()?, self.signed()?))
+    }
+
+    fn float(&mut self) -> Result<f32, FormatError> {
+        Ok(f32::from_bits(self.read(32)? as u32))
+    }
+
+    fn object(&mut self) -> Result<GameObject, FormatError> {
+        match self.read(3)? {
+            0 => Ok(GameObject::Player),
+            1 => Ok(GameObject::Monster),
+            2 => Ok(GameObject::CheckPoint { priority: usize::try_from(self.number(7)?).map_err(|_| FormatError::InvalidValue)? }),
+            3 => Ok(GameObject::Wall),
+            4 => Ok(GameObject::Projectile),
+            _ => Err(FormatError::InvalidValue)
+        }
+    }
+
+    fn controller(&mut self) -> Result<ControllerKind, FormatError> {
+        match self.read(2)? {
+            0 => Ok(ControllerKind::Player),
+            1 => Ok(ControllerKind::Monster),
+            2 => Ok(ControllerKind::BrainDead),
+            _ => Err(FormatError::InvalidValue)
+        }
+    }
+
+    /// Checks that only the padding of the last byte is left
+    fn finish(&self) -> Result<(), FormatError> {
+        if self.bytes.len() * 8 - self.position < 8 {
+            Ok(())
+        } else {
+            Err(FormatError::ByteAfterEnd)
+        }
+    }
+}
+
+/// The header is byte aligned, then the changes are bit-packed: each starts with the id and a 2 bits kind,
+/// updates then have a 5 bits mask of the fields that follow. Position and velocity components are zigzag
+/// encoded and prefixed with their length in bits, so that small differences with the baseline take few bits.
+impl Shareable for Snapshot {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.tick.encode(bytes);
+        self.baseline.encode(bytes);
+        self.time.encode(bytes);
+
+        let mut bits = BitWriter::default();
+        for (id, change) in self.changes.iter() {
+            bits.write(*id as u64, 64);
+            match change {
+                Change::Spawn(state) => {
+                    bits.write(0, 2);
+                    bits.object(&state.object);
+                    bits.controller(state.controller);
+                    bits.vector(state.position);
+                    bits.vector(state.velocity);
+                    bits.float(state.size.x);
+                    bits.float(state.size.y);
+                },
+                Change::Update(delta) => {
+                    bits.write(1, 2);
+                    bits.flag(delta.object.is_some());
+                    bits.flag(delta.controller.is_some());
+                    bits.flag(delta.position.is_some());
+                    bits.flag(delta.velocity.is_some());
+                    bits.flag(delta.size.is_some());
+
+                    if let Some(object) = &delta.object {
+                        bits.object(object);
+                    }
+                    if let Some(controller) = delta.controller {
+                        bits.controller(controller);
+                    }
+                    if let Some(position) = delta.position {
+                        bits.vector(position);
+                    }
+                    if let Some(velocity) = delta.velocity {
+                        bits.vector(velocity);
+                    }
+                    if let Some(size) = delta.size {
+                        bits.float(size.x);
+                        bits.float(size.y);
+                    }
+                },
+                Change::Despawn => bits.write(2, 2)
+            }
+        }
+
+        (self.changes.len() as u32).encode(bytes);
+        bytes.extend_from_slice(&bits.bytes);
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, FormatError> {
+        let tick = reader.read()?;
+        let baseline = reader.read()?;
+        let time = reader.read()?;
+        let count = reader.read::<u32>()?;
+
+        let mut bits = BitReader::new(reader.rest());
+        let mut changes = Vec::new();
+        for _ in 0..count {
+            let id = usize::try_from(bits.read(64)?).map_err(|_| FormatError::InvalidValue)?;
+            let change = match bits.read(2)? {
+                0 => Change::Spawn(EntityState {
+                    object: bits.object()?,
+                    controller: bits.controller()?,
+                    position: bits.vector()?,
+                    velocity: bits.vector()?,
+                    size: vec2(bits.float()?, bits.float()?)
+                }),
+                1 => {
+                    let present = [bits.flag()?, bits.flag()?, bits.flag()?, bits.flag()?, bits.flag()?];
+                    Change::Update(StateDelta {
+                        object: if present[0] { Some(bits.object()?) } else { None },
+                        controller: if present[1] { Some(bits.controller()?) } else { None },
+                        position: if present[2] { Some(bits.vector()?) } else { None },
+                        velocity: if present[3] { Some(bits.vector()?) } else { None },
+                        size: if present[4] { Some(vec2(bits.float()?, bits.float()?)) } else { None }
+                    })
+                },
+                2 => Change::Despawn,
+                _ => return Err(FormatError::InvalidValue)
+            };
+            changes.push((id, change));
+        }
+        bits.finish()?;
+
+        Ok(Snapshot { tick, baseline, time, changes })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::game::component::GameComponent;
+    use crate::game::controller::Controller;
+    use crate::game::keys::PlayerInput;
+    use crate::game::map::Map;
+    use crate::network::Command;
+    use crate::network::replication::EntityDelta;
+
+    fn state(x: f32) -> EntityState {
+        EntityState::from(&Entity {
+            object: GameObject::Monster,
+            controller: ControllerKind::Monster,
+            position: vec2(x, -x),
+            velocity: vec2(0.5, 0.0),
+            size: vec2(50.0, 50.0)
+        })
+    }
+
+    #[test]
+    fn snapshots_round_trip_bit_packed() {
+        let mut old = World::new();
+        old.insert(1, state(0.0));
+        old.insert(2, state(10.0));
+        old.insert(3, state(20.0));
+
+        let mut new = old.clone();
+        new.remove(&2);
+        new.get_mut(&3).unwrap().position += ivec2(3, -100_000);
+        new.get_mut(&3).unwrap().object = GameObject::CheckPoint { priority: usize::MAX };
+        new.insert(u64::MAX as usize, state(-1e5));
+
+        for snapshot in [Snapshot::between(7, 100, Some((5, &old)), &new), Snapshot::between(8, 120, None, &new)] {
+            let command = Command::Snapshot(snapshot.clone());
+            assert_eq!(Command::decode(&command.as_bytes()), Ok(command));
+            assert_eq!(snapshot.apply(&old), new);
+        }
+
+        let delta = Snapshot::between(7, 100, Some((5, &old)), &new);
+        assert_eq!(delta.changes.len(), 3);
+        assert!(delta.changes.contains(&(2, Change::Despawn)));
+    }
+
+    #[test]
+    fn deltas_build_on_acknowledged_snapshots() {
+        let mut history = SnapshotHistory::default();
+        let mut received = ReceivedSnapshots::default();
+
+        let mut world = World::new();
+        world.insert(1, state(0.0));
+        let full = history.next(1, 0, world.clone(), None).unwrap();
+        assert_eq!(full.baseline, None);
+
+        // Lost on the way: the next one is still full
+        world.get_mut(&1).unwrap().position.x += 16;
+        let lost = history.next(2, 16, world.clone(), None).unwrap();
+        assert_eq!(lost.baseline, None);
+        assert_eq!(received.receive(&full), Some(&history.sent[0].1));
+
+        world.get_mut(&1).unwrap().position.x += 16;
+        let delta = history.next(3, 33, world.clone(), Some(1)).unwrap();
+        assert_eq!(delta.baseline, Some(1));
+        assert_eq!(received.receive(&delta), Some(&world));
+        // Late snapshots are ignored
+        assert_eq!(received.receive(&lost), None);
+
+        // Nothing to say until something changes
+        assert_eq!(history.next(4, 50, world.clone(), Some(3)), None);
+
+        // Acknowledgements that stop coming lead to a full snapshot
+        for tick in 5..(5 + SnapshotHistory::MAX_UNACKNOWLEDGED as u32) {
+            world.get_mut(&1).unwrap().position.y += 1;
+            assert!(history.next(tick, 0, world.clone(), Some(3)).unwrap().baseline.is_some());
+        }
+        world.get_mut(&1).unwrap().position.y += 1;
+        assert_eq!(history.next(100, 0, world.clone(), Some(3)).unwrap().baseline, None);
+    }
+
+    /// Bytes per tick sent to each of 4 players while they and 100 monsters move around
+    #[test]
+    fn bandwidth_of_four_players_among_a_hundred_monsters() {
+        const TICKS: usize = 120;
+
+        let map = Map::generate(5, 5, 1);
+        let mut components = (0..104)
+            .map(|index| {
+                let position = Map::spawn_point() + vec2((index % 10) as f32 * 60.0, (index / 10) as f32 * 60.0);
+                let component = if index < 4 {
+                    let mut player = GameComponent::from(GameObject::Player);
+                    player.controller = Controller::Remote { input: PlayerInput { slide: vec2(1.0, 0.5).normalize(), ..Default::default() }, speed: 100.0 };
+                    player
+                } else {
+                    GameComponent::from(GameObject::Monster).with_controller(Controller::Monster)
+                };
+                let body = component.body.clone().with_position(position);
+                (index, component.with_body(body))
+            })
+            .collect::<Vec<_>>();
+
+        let mut histories = (0..4).map(|_| SnapshotHistory::default()).collect::<Vec<_>>();
+        let mut acknowledged = [None; 4];
+        let (mut full, mut deltas, mut naive) = (0, 0, 0);
+
+        for tick in 1..=TICKS as u32 {
+            for (_, component) in components.iter_mut() {
+                component.step(&map, 1.0 / 60.0);
+            }
+
+            for (player, history) in histories.iter_mut().enumerate() {
+                let world = components
+                    .iter()
+                    .filter(|(id, _)| *id != player)
+                    .map(|(id, component)| (*id, EntityState::from(&Entity::from(component))))
+                    .collect::<World>();
+                let Some(snapshot) = history.next(tick, tick as u64 * 16, world, acknowledged[player]) else { continue };
+
+                let size = Command::Snapshot(snapshot.clone()).as_bytes().len();
+                if snapshot.baseline.is_none() {
+                    full += size;
+                } else {
+                    deltas += size;
+                }
+                acknowledged[player] = Some(tick);
+            }
+
+            // What a player was sent before snapshots: a full precision update of every other entity
+            naive += components
+                .iter()
+                .skip(1)
+                .map(|(id, component)| Command::Update(*id, 0, EntityDelta {
+                    position: Some(component.body.position),
+                    velocity: Some(component.body.velocity),
+                    ..Default::default()
+                }).as_bytes().len())
+                .sum::<usize>();
+        }
+
+        let per_tick = deltas / (TICKS - 1) / 4;
+        let before = naive / TICKS;
+        println!("Full snapshot: {} bytes, then {per_tick} bytes per tick and per player instead of {before}", full / 4);
+        assert!(full / 4 < 103 * 40);
+        assert!(per_tick < before / 2);
+    }
+}
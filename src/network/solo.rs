@@ -0,0 +1,84 @@
+use crate::utils::{ Controlable, Drawable, Dynamic };
+
+use super::client::{ ClientConnectionError, GameClient };
+use super::server::{ GameServer, Scheduling };
+use super::GameAgent;
+
+/// A server and a player of this process, the player reaching the server without any socket.
+/// Other players may join if the server listens on the network, the local one being a client like them.
+pub struct Solo {
+    // Dropped first, so that it leaves before the server shuts down
+    client: GameClient,
+    server: GameServer
+}
+
+impl Solo {
+    /// A game nobody else can join
+    pub fn new(name: &str) -> Result<Self, ClientConnectionError> {
+        // A single in-process client does not need a thread of its own
+        Self::with_server(GameServer::offline().with_scheduling(Scheduling::EventLoop), name)
+    }
+    
+    /// Joins `server` as `name`, through an in-process connection
+    pub fn with_server(mut server: GameServer, name: &str) -> Result<Self, ClientConnectionError> {
+        let connector = server.connector();
+        let client = GameClient::in_memory(connector, name, || {
+            server.update();
+            server.wait();
+        })?;
+        
+        Ok(Self { client, server })
+    }
+}
+
+impl GameAgent for Solo {}
+
+impl Controlable for Solo {
+    fn handle_events(&mut self) -> bool {
+        self.client.handle_events()
+    }
+}
+
+impl Dynamic for Solo {
+    fn update(&mut self) {
+        self.server.update();
+        self.client.update();
+    }
+}
+
+impl Drawable for Solo {
+    fn draw(&self) {
+        self.client.draw();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    use std::net::TcpStream;
+    use std::time::Duration;
+    
+    use crate::network::{ Command, Protocol };
+    
+    #[test]
+    fn remote_players_meet_the_host() {
+        let server = GameServer::new("127.0.0.1:0").unwrap();
+        let address = server.address().unwrap();
+        let mut solo = Solo::with_server(server, "Host").unwrap();
+        
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let mut protocol = Protocol::new();
+        let hello = Command::Hello { version: Protocol::VERSION, name: String::from("Guest"), build: String::new(), session: None };
+        protocol.send(&mut stream, hello).unwrap();
+        
+        for _ in 0..500 {
+            solo.server.update();
+            if let Ok(Command::Snapshot(snapshot)) = protocol.reception(&mut stream) && !snapshot.changes.is_empty() {
+                return;
+            }
+        }
+        panic!("The host was not sent to the guest");
+    }
+}
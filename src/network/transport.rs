@@ -0,0 +1,196 @@
+use std::io::{ self, ErrorKind, Read, Write };
+use std::net::{ IpAddr, SocketAddr, TcpListener, TcpStream };
+use std::sync::mpsc::{ self, Receiver, Sender, TryRecvError };
+
+use super::readiness::{ self, Descriptor };
+
+/// An ordered, reliable and non-blocking byte stream to a single peer, which `Protocol` frames commands over
+pub trait Transport: Read + Write + Send {
+    /// Who the peer is, for the logs
+    fn peer(&self) -> String;
+    /// Address the peer connects from, `None` for in-process transports
+    fn ip(&self) -> Option<IpAddr>;
+    /// Socket to wait on for readiness, `None` for transports that must be checked periodically
+    fn descriptor(&self) -> Option<Descriptor>;
+}
+
+/// Where a server gets its connections from
+pub trait Listener: Send {
+    /// Next incoming connection, without blocking
+    fn accept(&mut self) -> Option<Box<dyn Transport>>;
+    /// Address the listener is bound to, if it is reachable from the network
+    fn local_address(&self) -> Option<SocketAddr>;
+    /// See `Transport::descriptor`
+    fn descriptor(&self) -> Option<Descriptor>;
+}
+
+impl Transport for TcpStream {
+    fn peer(&self) -> String {
+        self.peer_addr().map_or(String::from("an unknown address"), |address| address.to_string())
+    }
+
+    fn ip(&self) -> Option<IpAddr> {
+        self.peer_addr().ok().map(|address| address.ip())
+    }
+
+    fn descriptor(&self) -> Option<Descriptor> {
+        readiness::descriptor(self)
+    }
+}
+
+/// The listener must be non-blocking
+impl Listener for TcpListener {
+    fn accept(&mut self) -> Option<Box<dyn Transport>> {
+        let (stream, _) = TcpListener::accept(self).ok()?;
+        stream.set_nonblocking(true).ok()?;
+        Some(Box::new(stream))
+    }
+
+    fn local_address(&self) -> Option<SocketAddr> {
+        self.local_addr().ok()
+    }
+
+    fn descriptor(&self) -> Option<Descriptor> {
+        readiness::descriptor(self)
+    }
+}
+
+/// One end of an in-process connection. Reading from it never blocks,
+/// and the peer sees a disconnection once it is dropped.
+pub struct MemoryTransport {
+    incoming: Receiver<Vec<u8>>,
+    outgoing: Sender<Vec<u8>>,
+    /// Received bytes that were not read yet
+    pending: Vec<u8>
+}
+
+impl MemoryTransport {
+    pub fn pair() -> (Self, Self) {
+        let (a_out, b_in) = mpsc::channel();
+        let (b_out, a_in) = mpsc::channel();
+
+        (
+            Self { incoming: a_in, outgoing: a_out, pending: Vec::new() },
+            Self { incoming: b_in, outgoing: b_out, pending: Vec::new() }
+        )
+    }
+}
+
+impl Read for MemoryTransport {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            match self.incoming.try_recv() {
+                Ok(bytes) => self.pending = bytes,
+                Err(TryRecvError::Empty) => return Err(ErrorKind::WouldBlock.into()),
+                Err(TryRecvError::Disconnected) => return Ok(0)
+            }
+        }
+
+        let count = buffer.len().min(self.pending.len());
+        buffer[..count].copy_from_slice(&self.pending[..count]);
+        self.pending.drain(..count);
+        Ok(count)
+    }
+}
+
+impl Write for MemoryTransport {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        // An empty message would read as the end of the stream
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+
+        self.outgoing
+            .send(buffer.to_vec())
+            .map(|_| buffer.len())
+            .map_err(|_| ErrorKind::BrokenPipe.into())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn peer(&self) -> String {
+        String::from("this process")
+    }
+
+    fn ip(&self) -> Option<IpAddr> {
+        None
+    }
+
+    fn descriptor(&self) -> Option<Descriptor> {
+        None
+    }
+}
+
+/// Accepts the connections made through its `MemoryConnector`s
+pub struct MemoryListener {
+    connections: Receiver<MemoryTransport>
+}
+
+/// Connects to a `MemoryListener`, from any thread
+#[derive(Clone)]
+pub struct MemoryConnector {
+    listener: Sender<MemoryTransport>
+}
+
+impl MemoryListener {
+    pub fn new() -> (Self, MemoryConnector) {
+        let (listener, connections) = mpsc::channel();
+        (Self { connections }, MemoryConnector { listener })
+    }
+}
+
+impl Listener for MemoryListener {
+    fn accept(&mut self) -> Option<Box<dyn Transport>> {
+        self.connections.try_recv().ok().map(|transport| Box::new(transport) as Box<dyn Transport>)
+    }
+
+    fn local_address(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    fn descriptor(&self) -> Option<Descriptor> {
+        None
+    }
+}
+
+impl MemoryConnector {
+    /// Fails once the listener is dropped
+    pub fn connect(&self) -> Option<MemoryTransport> {
+        let (client, server) = MemoryTransport::pair();
+        self.listener.send(server).ok()?;
+        Some(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_transports_behave_like_non_blocking_streams() {
+        let (listener, connector) = MemoryListener::new();
+        let mut listener: Box<dyn Listener> = Box::new(listener);
+        assert!(listener.accept().is_none());
+
+        let mut client = connector.connect().unwrap();
+        let mut server = listener.accept().unwrap();
+
+        client.write_all(b"hello").unwrap();
+        client.write_all(b"!").unwrap();
+
+        let mut buffer = [0u8; 4];
+        assert_eq!(server.read(&mut buffer).unwrap(), 4);
+        assert_eq!(server.read(&mut buffer).unwrap(), 1);
+        assert_eq!(server.read(&mut buffer).unwrap(), 1);
+        assert_eq!(&buffer[..1], b"!");
+        assert_eq!(server.read(&mut buffer).unwrap_err().kind(), ErrorKind::WouldBlock);
+
+        drop(client);
+        assert_eq!(server.read(&mut buffer).unwrap(), 0);
+        assert_eq!(server.write(b"late").unwrap_err().kind(), ErrorKind::BrokenPipe);
+    }
+}
@@ -1,12 +1,35 @@
 use std::ffi::c_void;
 use std::mem;
 use std::ops::{ Index, IndexMut };
+use std::sync::atomic::{ AtomicBool, Ordering };
 
 unsafe extern "C" {
     pub fn srand(seed: usize);
     fn rand() -> usize;
     
     fn time(ptr: *mut c_void) -> usize;
+    
+    fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Ctrl+C, caught so that the program can stop cleanly
+pub struct Interruption;
+impl Interruption {
+    const SIGINT: i32 = 2;
+    
+    pub fn catch() {
+        unsafe { signal(Self::SIGINT, Self::handle) };
+    }
+    
+    pub fn requested() -> bool {
+        INTERRUPTED.load(Ordering::Relaxed)
+    }
+    
+    extern "C" fn handle(_signum: i32) {
+        INTERRUPTED.store(true, Ordering::Relaxed);
+    }
 }
 
 pub struct Time;
@@ -38,6 +61,14 @@ impl Random {
         }     
     }
     
+    /// Unpredictable value, independent from the seeded generator.
+    /// Used for secrets such as session tokens.
+    pub fn token() -> u64 {
+        use std::hash::{ BuildHasher, RandomState };
+        
+        RandomState::new().hash_one(Time::unix_epoch())
+    }
+    
     pub fn max(i: usize) -> usize {
         Self::any() % i
     }